@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::fmt::{self, Display, Formatter};
+
+use http_types::mime;
+use humantime::format_rfc3339_seconds;
+use serde::Serialize;
+use serde_json::json;
+use tide::{Request, Response};
+
+use crate::github::PullRequestStatus;
+use crate::project::Project;
+use crate::tree::Tree;
+use crate::types::{self, PrNumber};
+use crate::{project, track_pr_and_backports, Query, TrackError, TrackedPr};
+
+/// Why a PR couldn't be shown.
+#[derive(Debug)]
+enum Error {
+    NoProject(String),
+    Number(types::Error),
+    Track(TrackError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            NoProject(repo) => write!(f, "No such project: {}.", repo),
+            Number(e) => write!(f, "{}", e),
+            Track(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    fn status(&self) -> u16 {
+        use Error::*;
+        match self {
+            NoProject(_) => 404,
+            Number(_) => 400,
+            Track(e) => e.status(),
+        }
+    }
+}
+
+/// The status to respond with about a PR that could be shown.
+fn pr_status(status: &PullRequestStatus) -> u16 {
+    match status {
+        PullRequestStatus::Closed => 410,
+        _ => 200,
+    }
+}
+
+#[derive(Serialize)]
+struct PrResponse<'a> {
+    repo: &'a str,
+    number: PrNumber,
+    title: &'a str,
+    link: String,
+    status: &'static str,
+    merge_commit: Option<&'a str>,
+    warning: Option<&'static str>,
+    /// Joined with the trees of the PR's backports, so a branch has
+    /// been reached if any of them reached it.
+    tree: Option<Tree>,
+    backports: Vec<BackportResponse<'a>>,
+    /// The trees of backports to branches that aren't in `tree`.
+    backport_trees: Vec<Tree>,
+    /// When the branches in the tree were last fetched.
+    last_fetch: Option<String>,
+}
+
+/// A PR that backports the one asked about to another branch.
+#[derive(Serialize)]
+struct BackportResponse<'a> {
+    number: PrNumber,
+    title: &'a str,
+    link: String,
+}
+
+fn json_response(status: u16, body: impl Serialize) -> http_types::Result<Response> {
+    Ok(Response::builder(status)
+        .content_type(mime::JSON)
+        .body(serde_json::to_vec(&body)?)
+        .build())
+}
+
+fn error_response(status: u16, message: impl ToString) -> http_types::Result<Response> {
+    json_response(status, json!({ "error": message.to_string() }))
+}
+
+/// Returns the same information as the HTML page for a PR, as JSON.
+///
+/// Closed PRs are reported with 410 Gone, since they will never reach
+/// any branch.
+pub async fn pr<S>(request: Request<S>) -> http_types::Result<Response> {
+    let Query { repo, .. } = request.query()?;
+    let number = request.param("number")?;
+
+    let (project, mut pr) = match find(repo, number).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e.status(), e),
+    };
+
+    let status = match pr.status {
+        PullRequestStatus::Open => "open",
+        PullRequestStatus::Closed => "closed",
+        PullRequestStatus::Merged { .. } => "merged",
+    };
+
+    let has_tree = pr.tree.is_some();
+    let mut trees = Tree::union(pr.union_trees());
+    let tree = has_tree.then(|| trees.remove(0));
+
+    let body = PrResponse {
+        repo: &project.name,
+        number: pr.number,
+        title: &pr.title,
+        link: project.pull_link(pr.number),
+        status,
+        merge_commit: pr.merge_commit_oid(),
+        warning: pr.warning(),
+        tree,
+        backports: pr
+            .backports
+            .iter()
+            .map(|backport| BackportResponse {
+                number: backport.number,
+                title: &backport.title,
+                link: project.pull_link(backport.number),
+            })
+            .collect(),
+        backport_trees: trees,
+        last_fetch: project
+            .last_fetch()
+            .map(|time| format_rfc3339_seconds(time).to_string()),
+    };
+
+    json_response(pr_status(&pr.status), body)
+}
+
+async fn find(repo: Option<String>, number: &str) -> Result<(&'static Project, TrackedPr), Error> {
+    let project =
+        project::find(repo.as_deref()).ok_or_else(|| Error::NoProject(repo.unwrap_or_default()))?;
+    let number = number.parse().map_err(Error::Number)?;
+    let pr = track_pr_and_backports(project, number)
+        .await
+        .map_err(Error::Track)?;
+    Ok((project, pr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::github;
+
+    #[test]
+    fn statuses() {
+        let number = "123".parse().unwrap();
+        let not_found = TrackError::NotFound {
+            owner: "NixOS".to_string(),
+            repo: "nixpkgs".to_string(),
+            number,
+        };
+        let github =
+            TrackError::GitHub(github::Error::Response(http_types::StatusCode::BadGateway));
+
+        assert_eq!(
+            Error::Number("abc".parse::<PrNumber>().unwrap_err()).status(),
+            400
+        );
+        assert_eq!(Error::NoProject("nonexistent".to_string()).status(), 404);
+        assert_eq!(Error::Track(not_found).status(), 404);
+        assert_eq!(Error::Track(github).status(), 502);
+
+        assert_eq!(pr_status(&PullRequestStatus::Open), 200);
+        let merged = PullRequestStatus::Merged {
+            merge_commit_oid: None,
+        };
+        assert_eq!(pr_status(&merged), 200);
+        assert_eq!(pr_status(&PullRequestStatus::Closed), 410);
+    }
+}
@@ -3,10 +3,20 @@
 // SPDX-FileCopyrightText: 2022 Arnout Engelen <arnout@bzzt.net>
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
 
-use once_cell::sync::Lazy;
 use regex::{Regex, RegexSet};
+use serde::Deserialize;
+use toml::Spanned;
+
+/// How many branches deep the rules are followed, so that rules that
+/// keep making new branches from old ones can't go on forever.
+pub const MAX_DEPTH: usize = 32;
 
 const NEXT_BRANCH_TABLE: [(&str, &str); 12] = [
     (r"\Apython-updates\z", "staging"),
@@ -42,91 +52,459 @@ const CHANNEL_HYDRA_LINK_TABLE: [(&str, &str); 4] = [
     (r"\Anixos-(\d.*)\z", "nixos/release-$1/tested"),
 ];
 
-static BRANCH_NEXTS: Lazy<BTreeMap<&str, Vec<&str>>> = Lazy::new(|| {
-    NEXT_BRANCH_TABLE
-        .iter()
-        .fold(BTreeMap::new(), |mut map, (pattern, next)| {
-            map.entry(pattern).or_insert_with(Vec::new).push(next);
-            map
-        })
-});
-
-static BRANCH_NEXTS_BY_INDEX: Lazy<Vec<&Vec<&str>>> = Lazy::new(|| BRANCH_NEXTS.values().collect());
-
-static BRANCH_PATTERNS: Lazy<Vec<Regex>> = Lazy::new(|| {
-    BRANCH_NEXTS
-        .keys()
-        .copied()
-        .map(Regex::new)
-        .map(Result::unwrap)
-        .collect()
-});
-
-static BRANCH_REGEXES: Lazy<RegexSet> = Lazy::new(|| RegexSet::new(BRANCH_NEXTS.keys()).unwrap());
-
-pub fn next_branches(branch: &str) -> Vec<Cow<str>> {
-    BRANCH_REGEXES
-        .matches(branch)
-        .iter()
-        .flat_map(|index| {
-            let regex = BRANCH_PATTERNS.get(index).unwrap();
-            BRANCH_NEXTS_BY_INDEX
-                .get(index)
-                .unwrap()
+/// A rule as written in the configuration, along with where it came
+/// from so errors can point at it.
+struct Rule<'a> {
+    pattern: &'a str,
+    pattern_line: Option<usize>,
+    replacement: &'a str,
+    replacement_line: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Json(serde_json::Error),
+    Regex {
+        line: Option<usize>,
+        error: regex::Error,
+    },
+    Capture {
+        line: Option<usize>,
+        capture: String,
+        pattern: String,
+    },
+    /// Following the rule on `line` leads back to `branch`, which the
+    /// rules had already passed through.
+    Cycle {
+        line: Option<usize>,
+        branch: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        let at = |line: &Option<usize>| match line {
+            Some(line) => format!("line {}: ", line),
+            None => String::new(),
+        };
+        match self {
+            Io(e) => write!(f, "{}", e),
+            Parse(e) => write!(f, "{}", e),
+            Json(e) => write!(f, "{}", e),
+            Regex { line, error } => write!(f, "{}{}", at(line), error),
+            Capture {
+                line,
+                capture,
+                pattern,
+            } => write!(
+                f,
+                "{}${} does not refer to a capture group of {}",
+                at(line),
+                capture,
+                pattern
+            ),
+            Cycle { line, branch } => {
+                write!(f, "{}{} is merged back into itself", at(line), branch)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns the capture group references in a replacement template, in
+/// the syntax accepted by [`Regex::replace`].
+pub(crate) fn capture_references(template: &str) -> Vec<(Range<usize>, &str)> {
+    let bytes = template.as_bytes();
+    let mut references = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'$' {
+            i += 1;
+            continue;
+        }
+
+        if bytes.get(i + 1) == Some(&b'$') {
+            i += 2;
+            continue;
+        }
+
+        if bytes.get(i + 1) == Some(&b'{') {
+            if let Some(len) = template[i + 2..].find('}') {
+                let end = i + 2 + len + 1;
+                references.push((i..end, &template[i + 2..end - 1]));
+                i = end;
+                continue;
+            }
+            i += 1;
+            continue;
+        }
+
+        let len = template[i + 1..]
+            .bytes()
+            .take_while(|b| b.is_ascii_alphanumeric() || *b == b'_')
+            .count();
+        if len > 0 {
+            references.push((i..i + 1 + len, &template[i + 1..i + 1 + len]));
+        }
+        i += 1 + len;
+    }
+
+    references
+}
+
+fn compile(rule: &Rule) -> Result<Regex, Error> {
+    let regex = Regex::new(rule.pattern).map_err(|error| Error::Regex {
+        line: rule.pattern_line,
+        error,
+    })?;
+
+    for (_, capture) in capture_references(rule.replacement) {
+        let resolves = match capture.parse::<usize>() {
+            Ok(index) => index < regex.captures_len(),
+            Err(_) => regex.capture_names().flatten().any(|name| name == capture),
+        };
+        if !resolves {
+            return Err(Error::Capture {
+                line: rule.replacement_line,
+                capture: capture.to_string(),
+                pattern: rule.pattern.to_string(),
+            });
+        }
+    }
+
+    Ok(regex)
+}
+
+/// Follows `rules` from every branch they name outright, and rejects
+/// the first rule that leads back to a branch on the way there.
+/// Branches made from capture groups can't all be followed, so trees
+/// stop at branches they've already been through.
+fn check_cycles(rules: &[Rule]) -> Result<(), Error> {
+    fn visit(
+        branch: String,
+        rules: &[Rule],
+        regexes: &[Regex],
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+    ) -> Result<(), Error> {
+        if path.len() >= MAX_DEPTH || done.contains(&branch) {
+            return Ok(());
+        }
+        path.push(branch);
+        let branch = path.last().unwrap().clone();
+        for (rule, regex) in rules.iter().zip(regexes) {
+            if !regex.is_match(&branch) {
+                continue;
+            }
+            let next = regex.replace(&branch, rule.replacement).into_owned();
+            if path.contains(&next) {
+                return Err(Error::Cycle {
+                    line: rule.replacement_line,
+                    branch: next,
+                });
+            }
+            visit(next, rules, regexes, path, done)?;
+        }
+        path.pop();
+        done.insert(branch);
+        Ok(())
+    }
+
+    let regexes: Vec<_> = rules.iter().map(compile).collect::<Result<_, _>>()?;
+    let mut done = HashSet::new();
+    for rule in rules {
+        if capture_references(rule.replacement).is_empty() {
+            let branch = rule.replacement.replace("$$", "$");
+            visit(branch, rules, &regexes, &mut Vec::new(), &mut done)?;
+        }
+    }
+    Ok(())
+}
+
+/// The rules describing which branches a branch is merged into, and
+/// where each branch can be found on Hydra.
+#[derive(Debug)]
+pub struct BranchRules {
+    next_patterns: Vec<Regex>,
+    next_regexes: RegexSet,
+    nexts: Vec<Vec<String>>,
+    hydra_link_patterns: Vec<Regex>,
+    hydra_link_regexes: RegexSet,
+    hydra_links: Vec<String>,
+}
+
+/// The configuration file, with strings of type `S`, which in TOML
+/// know where they are in the file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BranchConfig<S> {
+    #[serde(default = "Vec::new")]
+    next: Vec<NextConfig<S>>,
+    #[serde(default = "Vec::new")]
+    hydra: Vec<HydraConfig<S>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NextConfig<S> {
+    pattern: S,
+    next: Vec<S>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HydraConfig<S> {
+    pattern: S,
+    link: S,
+}
+
+impl BranchRules {
+    fn new(nexts: &[Rule], hydra_links: &[Rule]) -> Result<Self, Error> {
+        // Rules sharing a pattern are grouped together, so that a
+        // branch's next branches come out in the order they were
+        // written in.
+        let mut next_patterns: Vec<Regex> = Vec::new();
+        let mut next_sources: Vec<&str> = Vec::new();
+        let mut next_replacements: Vec<Vec<String>> = Vec::new();
+        for rule in nexts {
+            let regex = compile(rule)?;
+            match next_sources.iter().position(|p| *p == rule.pattern) {
+                Some(index) => next_replacements[index].push(rule.replacement.to_string()),
+                None => {
+                    next_patterns.push(regex);
+                    next_sources.push(rule.pattern);
+                    next_replacements.push(vec![rule.replacement.to_string()]);
+                }
+            }
+        }
+
+        let hydra_link_patterns = hydra_links
+            .iter()
+            .map(compile)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            next_regexes: RegexSet::new(next_patterns.iter().map(Regex::as_str)).unwrap(),
+            next_patterns,
+            nexts: next_replacements,
+            hydra_link_regexes: RegexSet::new(hydra_link_patterns.iter().map(Regex::as_str))
+                .unwrap(),
+            hydra_link_patterns,
+            hydra_links: hydra_links
                 .iter()
-                .map(move |next| regex.replace(branch, *next))
+                .map(|rule| rule.replacement.to_string())
+                .collect(),
         })
-        .collect()
+    }
+
+    /// Makes rules from a configuration file, where `locate` gives a
+    /// string's value and the line it's on, if known.
+    fn from_config<'c, S>(
+        config: &'c BranchConfig<S>,
+        locate: impl Fn(&S) -> (&str, Option<usize>),
+    ) -> Result<Self, Error> {
+        let rule = |pattern: &'c S, replacement: &'c S| {
+            let (pattern, pattern_line) = locate(pattern);
+            let (replacement, replacement_line) = locate(replacement);
+            Rule {
+                pattern,
+                pattern_line,
+                replacement,
+                replacement_line,
+            }
+        };
+
+        let nexts: Vec<_> = config
+            .next
+            .iter()
+            .flat_map(|next| next.next.iter().map(|n| rule(&next.pattern, n)))
+            .collect();
+
+        // Check every pattern, even those that have no next branches.
+        for next in &config.next {
+            let (pattern, pattern_line) = locate(&next.pattern);
+            compile(&Rule {
+                pattern,
+                pattern_line,
+                replacement: "",
+                replacement_line: None,
+            })?;
+        }
+
+        let hydra_links: Vec<_> = config
+            .hydra
+            .iter()
+            .map(|hydra| rule(&hydra.pattern, &hydra.link))
+            .collect();
+
+        let rules = Self::new(&nexts, &hydra_links)?;
+        check_cycles(&nexts)?;
+        Ok(rules)
+    }
+
+    /// Parses branch rules from the contents of a TOML configuration
+    /// file, rejecting invalid patterns and rules that go round in
+    /// circles with the line they're on.
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let config: BranchConfig<Spanned<String>> = toml::from_str(source).map_err(Error::Parse)?;
+        let line = |span: Range<usize>| Some(source[..span.start].matches('\n').count() + 1);
+        Self::from_config(&config, |s| (s.get_ref(), line(s.span())))
+    }
+
+    /// Parses branch rules from the contents of a JSON configuration
+    /// file, with the same fields as TOML.  JSON doesn't say where
+    /// strings are, so errors in rules can't point at their lines.
+    pub fn parse_json(source: &str) -> Result<Self, Error> {
+        let config: BranchConfig<String> = serde_json::from_str(source).map_err(Error::Json)?;
+        Self::from_config(&config, |s| (s, None))
+    }
+
+    /// Loads branch rules from a file, which is JSON if its name ends
+    /// in `.json`, and TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let source = read_to_string(path).map_err(Error::Io)?;
+        match path.extension() {
+            Some(extension) if extension == "json" => Self::parse_json(&source),
+            _ => Self::parse(&source),
+        }
+    }
+
+    /// Describes each rule in a line of its own, so that changes to
+    /// the rules can be logged.
+    pub fn describe(&self) -> BTreeSet<String> {
+        let nexts = self
+            .next_patterns
+            .iter()
+            .zip(&self.nexts)
+            .flat_map(|(pattern, nexts)| {
+                nexts
+                    .iter()
+                    .map(move |next| format!("next {} -> {}", pattern, next))
+            });
+        let hydra_links = self
+            .hydra_link_patterns
+            .iter()
+            .zip(&self.hydra_links)
+            .map(|(pattern, link)| format!("hydra {} -> {}", pattern, link));
+        nexts.chain(hydra_links).collect()
+    }
+
+    /// Returns git refspec patterns matching every branch that a
+    /// branch can be merged into, with `*` standing in for anything
+    /// taken from a capture group.
+    pub fn next_branch_globs(&self) -> BTreeSet<String> {
+        self.nexts
+            .iter()
+            .flatten()
+            .map(|next| {
+                // Refspecs can only have one `*`, so if there are several
+                // captures, everything between them is a wildcard too.
+                let references = capture_references(next);
+                let (prefix, suffix) = match (references.first(), references.last()) {
+                    (Some((first, _)), Some((last, _))) => {
+                        (&next[..first.start], Some(&next[last.end..]))
+                    }
+                    _ => (next.as_str(), None),
+                };
+                let prefix = prefix.replace("$$", "$");
+                match suffix {
+                    Some(suffix) => format!("{}*{}", prefix, suffix.replace("$$", "$")),
+                    None => prefix,
+                }
+            })
+            .collect()
+    }
+
+    pub fn next_branches<'b>(&self, branch: &'b str) -> Vec<Cow<'b, str>> {
+        self.next_regexes
+            .matches(branch)
+            .iter()
+            .flat_map(|index| {
+                let regex = self.next_patterns.get(index).unwrap();
+                self.nexts
+                    .get(index)
+                    .unwrap()
+                    .iter()
+                    .map(move |next| regex.replace(branch, next.as_str()))
+            })
+            .collect()
+    }
+
+    pub fn branch_hydra_link(&self, branch: &str) -> Option<String> {
+        self.hydra_link_regexes
+            .matches(branch)
+            .iter()
+            .next()
+            .and_then(|index| {
+                let regex = self.hydra_link_patterns.get(index).unwrap();
+                self.hydra_links
+                    .get(index)
+                    .map(move |link| regex.replace(branch, link.as_str()).to_string())
+            })
+    }
 }
 
-static BRANCH_HYDRA_LINK_PATTERNS: Lazy<Vec<Regex>> = Lazy::new(|| {
-    BRANCH_HYDRA_LINKS
-        .keys()
-        .copied()
-        .map(Regex::new)
-        .map(Result::unwrap)
-        .collect()
-});
-
-static BRANCH_HYDRA_LINKS: Lazy<BTreeMap<&str, String>> = Lazy::new(|| {
-    let branch_links = BRANCH_HYDRA_LINK_TABLE.iter().map(|(pattern, jobset)| {
-        (
-            *pattern,
-            format!("https://hydra.nixos.org/jobset/{jobset}#tabs-jobs"),
-        )
-    });
-    let channel_links = CHANNEL_HYDRA_LINK_TABLE.iter().map(|(pattern, job)| {
-        (
-            *pattern,
-            format!("https://hydra.nixos.org/job/{job}#tabs-constituents"),
-        )
-    });
-    branch_links.chain(channel_links).collect()
-});
-
-static BRANCH_HYDRA_LINKS_BY_INDEX: Lazy<Vec<String>> =
-    Lazy::new(|| BRANCH_HYDRA_LINKS.values().cloned().collect());
-
-static BRANCH_HYDRA_LINK_REGEXES: Lazy<RegexSet> =
-    Lazy::new(|| RegexSet::new(BRANCH_HYDRA_LINKS.keys()).unwrap());
-
-pub fn branch_hydra_link(branch: &str) -> Option<String> {
-    BRANCH_HYDRA_LINK_REGEXES
-        .matches(branch)
-        .iter()
-        .next()
-        .and_then(|index| {
-            let regex = BRANCH_HYDRA_LINK_PATTERNS.get(index).unwrap();
-            BRANCH_HYDRA_LINKS_BY_INDEX
-                .get(index)
-                .map(move |link| regex.replace(branch, link).to_string())
-        })
+impl Default for BranchRules {
+    fn default() -> Self {
+        let nexts: Vec<_> = NEXT_BRANCH_TABLE
+            .iter()
+            .map(|(pattern, next)| Rule {
+                pattern,
+                pattern_line: None,
+                replacement: next,
+                replacement_line: None,
+            })
+            .collect();
+
+        let branch_links = BRANCH_HYDRA_LINK_TABLE.iter().map(|(pattern, jobset)| {
+            (
+                *pattern,
+                format!("https://hydra.nixos.org/jobset/{jobset}#tabs-jobs"),
+            )
+        });
+        let channel_links = CHANNEL_HYDRA_LINK_TABLE.iter().map(|(pattern, job)| {
+            (
+                *pattern,
+                format!("https://hydra.nixos.org/job/{job}#tabs-constituents"),
+            )
+        });
+        let links: Vec<_> = branch_links.chain(channel_links).collect();
+        let hydra_links: Vec<_> = links
+            .iter()
+            .map(|(pattern, link)| Rule {
+                pattern,
+                pattern_line: None,
+                replacement: link,
+                replacement_line: None,
+            })
+            .collect();
+
+        check_cycles(&nexts).unwrap();
+        Self::new(&nexts, &hydra_links).unwrap()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use once_cell::sync::Lazy;
+
     use super::*;
 
+    static RULES: Lazy<BranchRules> = Lazy::new(BranchRules::default);
+
+    fn next_branches(branch: &str) -> Vec<Cow<'_, str>> {
+        RULES.next_branches(branch)
+    }
+
+    fn branch_hydra_link(branch: &str) -> Option<String> {
+        RULES.branch_hydra_link(branch)
+    }
+
     #[test]
     fn python_updates() {
         let res = next_branches("python-updates");
@@ -266,4 +644,121 @@ mod tests {
         let expected = "https://hydra.nixos.org/job/nixos/trunk-combined/tested#tabs-constituents";
         assert_eq!(link.unwrap(), expected);
     }
+
+    #[test]
+    fn config() {
+        let rules = BranchRules::parse(
+            r#"
+[[next]]
+pattern = '\Amain\z'
+next = ["stable", "unstable"]
+
+[[next]]
+pattern = '\Arelease-(?<version>[\d.]+)\z'
+next = ["stable-$version"]
+
+[[hydra]]
+pattern = '\Astable-(.*)\z'
+link = "https://hydra.example.org/jobset/stable/$1"
+"#,
+        )
+        .unwrap();
+        assert_eq!(rules.next_branches("main"), vec!["stable", "unstable"]);
+        assert_eq!(rules.next_branches("release-1.0"), vec!["stable-1.0"]);
+        assert!(rules.next_branches("stable-1.0").is_empty());
+        let link = rules.branch_hydra_link("stable-1.0");
+        assert_eq!(link.unwrap(), "https://hydra.example.org/jobset/stable/1.0");
+    }
+
+    #[test]
+    fn config_bad_regex() {
+        let source = "[[next]]\npattern = 'release-('\nnext = []\n";
+        match BranchRules::parse(source) {
+            Err(Error::Regex { line, .. }) => assert_eq!(line, Some(2)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn config_unresolvable_capture() {
+        let source =
+            "[[next]]\npattern = 'release-(.*)'\nnext = [\n  \"nixos-$1\",\n  \"nixos-$2\",\n]\n";
+        match BranchRules::parse(source) {
+            Err(Error::Capture { line, capture, .. }) => {
+                assert_eq!(line, Some(5));
+                assert_eq!(capture, "2");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn config_unknown_named_capture() {
+        let source =
+            "[[hydra]]\npattern = 'nixos-(?<v>.*)'\nlink = 'https://hydra.nixos.org/${version}'\n";
+        assert!(matches!(
+            BranchRules::parse(source),
+            Err(Error::Capture { line: Some(3), .. })
+        ));
+    }
+
+    #[test]
+    fn config_cycle() {
+        let source = "[[next]]\npattern = '\\Amain\\z'\nnext = [\"stable\"]\n\n[[next]]\npattern = '\\Astable\\z'\nnext = [\n  \"lts\",\n  \"main\",\n]\n";
+        match BranchRules::parse(source) {
+            Err(Error::Cycle { line, branch }) => {
+                assert_eq!(line, Some(3));
+                assert_eq!(branch, "stable");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Rules that only go round in circles through capture groups
+        // can't be found, but don't stop the rest of the rules working.
+        let source = "[[next]]\npattern = '\\Aa-(.*)'\nnext = [\"b-$1\"]\n\n[[next]]\npattern = '\\Ab-(.*)'\nnext = [\"a-$1\"]\n";
+        let rules = BranchRules::parse(source).unwrap();
+        assert_eq!(rules.next_branches("a-1"), vec!["b-1"]);
+    }
+
+    #[test]
+    fn config_json() {
+        let rules = BranchRules::parse_json(
+            r#"{
+                "next": [{ "pattern": "\\Amain\\z", "next": ["stable"] }],
+                "hydra": [{ "pattern": "\\Astable\\z", "link": "https://hydra.example.org" }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(rules.next_branches("main"), vec!["stable"]);
+        let link = rules.branch_hydra_link("stable");
+        assert_eq!(link.unwrap(), "https://hydra.example.org");
+
+        let source = r#"{ "next": [{ "pattern": "\\Amain\\z", "next": ["main"] }] }"#;
+        assert!(matches!(
+            BranchRules::parse_json(source),
+            Err(Error::Cycle { line: None, .. })
+        ));
+        assert!(matches!(
+            BranchRules::parse_json(r#"{ "nexts": [] }"#),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn globs() {
+        let globs = RULES.next_branch_globs();
+        let expected = [
+            "master",
+            "nixos-*",
+            "nixos-*-small",
+            "nixos-unstable-small",
+            "nixpkgs-*-darwin",
+            "nixpkgs-unstable",
+            "release-*",
+            "staging",
+            "staging-next",
+            "staging-next-*",
+        ];
+        assert_eq!(globs, expected.into_iter().map(String::from).collect());
+    }
 }
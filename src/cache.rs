@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read, rename, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::github::{self, Found, GitHub, PrInfo, PullRequestStatus};
+use crate::types::{Filter, PrNumber};
+
+#[derive(Deserialize, Serialize)]
+struct Entry<T> {
+    /// Seconds since the epoch.
+    fetched_at: u64,
+    info: T,
+}
+
+impl<T> Entry<T> {
+    fn is_recent(&self, ttl: Duration) -> bool {
+        now().saturating_sub(self.fetched_at) < ttl.as_secs()
+    }
+}
+
+impl Entry<PrInfo> {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        match self.info.status {
+            // Once a PR has been merged or closed, nothing we care
+            // about changes any more.
+            PullRequestStatus::Merged { .. } | PullRequestStatus::Closed => true,
+            PullRequestStatus::Open => self.is_recent(ttl),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Looks up PRs on GitHub, remembering the answers on disk so that
+/// they survive restarts.
+pub struct Cache<'a> {
+    github: GitHub<'a>,
+    folder: &'a Path,
+    ttl: Duration,
+}
+
+impl<'a> Cache<'a> {
+    pub fn new(github: GitHub<'a>, folder: &'a Path, ttl: Duration) -> Self {
+        Self {
+            github,
+            folder,
+            ttl,
+        }
+    }
+
+    fn path(&self, owner: &str, repo: &str, pr: PrNumber) -> PathBuf {
+        self.folder
+            .join(owner)
+            .join(repo)
+            .join(format!("{}.json", pr))
+    }
+
+    fn backports_path(&self, owner: &str, repo: &str, pr: PrNumber) -> PathBuf {
+        self.folder
+            .join(owner)
+            .join(repo)
+            .join(format!("{}.backports.json", pr))
+    }
+
+    fn load<T: DeserializeOwned>(&self, path: &Path) -> Option<Entry<T>> {
+        serde_json::from_slice(&read(path).ok()?).ok()
+    }
+
+    fn store<T: Serialize>(&self, path: &Path, entry: &Entry<T>) -> io::Result<()> {
+        create_dir_all(path.parent().unwrap())?;
+        // Write to a temporary file first, so that a crash can't leave
+        // a truncated entry behind.
+        let temporary = path.with_extension("json.tmp");
+        write(&temporary, serde_json::to_vec(entry)?)?;
+        rename(temporary, path)
+    }
+
+    fn cached(&self, owner: &str, repo: &str, pr: PrNumber) -> Option<PrInfo> {
+        self.load(&self.path(owner, repo, pr))
+            .filter(|entry| entry.is_fresh(self.ttl))
+            .map(|entry| entry.info)
+    }
+
+    fn insert(&self, owner: &str, repo: &str, pr: PrNumber, info: PrInfo) -> PrInfo {
+        self.insert_at(self.path(owner, repo, pr), info)
+    }
+
+    fn insert_at<T: Serialize>(&self, path: PathBuf, info: T) -> T {
+        let entry = Entry {
+            fetched_at: now(),
+            info,
+        };
+
+        if let Err(e) = self.store(&path, &entry) {
+            eprintln!("pr-tracker: caching {}: {}", path.display(), e);
+        }
+
+        entry.info
+    }
+
+    pub async fn pr_info(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr: PrNumber,
+    ) -> Result<PrInfo, github::Error> {
+        if let Some(info) = self.cached(owner, repo, pr) {
+            return Ok(info);
+        }
+
+        let info = self.github.pr_info(owner, repo, pr).await?;
+        Ok(self.insert(owner, repo, pr, info))
+    }
+
+    /// Like [`Self::pr_info`], but looks up all the PRs that aren't
+    /// cached in as few requests as possible.
+    pub async fn pr_infos(
+        &self,
+        owner: &str,
+        repo: &str,
+        prs: &[PrNumber],
+        batch_size: usize,
+    ) -> HashMap<PrNumber, Result<PrInfo, github::Error>> {
+        let mut results = HashMap::new();
+        let mut missing = Vec::new();
+
+        for pr in prs {
+            match self.cached(owner, repo, *pr) {
+                Some(info) => {
+                    results.insert(*pr, Ok(info));
+                }
+                None => missing.push(*pr),
+            }
+        }
+
+        let fetched = self
+            .github
+            .pr_infos(owner, repo, &missing, batch_size)
+            .await;
+        for (pr, result) in fetched {
+            let result = result.map(|info| self.insert(owner, repo, pr, info));
+            results.insert(pr, result);
+        }
+
+        results
+    }
+
+    /// Backports can be opened at any time, even long after a PR was
+    /// merged, so they're only cached for as long as open PRs are.
+    pub async fn backports(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr: PrNumber,
+    ) -> Result<Vec<PrNumber>, github::Error> {
+        let path = self.backports_path(owner, repo, pr);
+        if let Some(entry) = self.load(&path).filter(|entry| entry.is_recent(self.ttl)) {
+            return Ok(entry.info);
+        }
+
+        let backports = self.github.backports(owner, repo, pr).await?;
+        Ok(self.insert_at(path, backports))
+    }
+
+    /// Searches aren't cached, since updates only make each one once.
+    pub async fn search_prs(
+        &self,
+        owner: &str,
+        repo: &str,
+        filter: &Filter,
+        since: &str,
+    ) -> Result<Found, github::Error> {
+        self.github.search_prs(owner, repo, filter, since).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsStr;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn pr_info(status: PullRequestStatus) -> PrInfo {
+        PrInfo {
+            branch: "master".to_string(),
+            title: "title".to_string(),
+            status,
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let folder = std::env::temp_dir().join(format!("pr-tracker-cache-{}", std::process::id()));
+        let github = GitHub::new(OsStr::new("token"), OsStr::new("pr-tracker"));
+        let cache = Cache::new(github, &folder, TTL);
+        let number = |n: i64| PrNumber::try_from(n).unwrap();
+        let store = |pr, age: u64, status| {
+            let entry = Entry {
+                fetched_at: now() - age,
+                info: pr_info(status),
+            };
+            cache
+                .store(&cache.path("NixOS", "nixpkgs", number(pr)), &entry)
+                .unwrap();
+        };
+        let cached = |pr| cache.cached("NixOS", "nixpkgs", number(pr)).is_some();
+
+        // Open PRs can still change, so they're only kept for the TTL.
+        store(1, 0, PullRequestStatus::Open);
+        assert!(cached(1));
+        store(1, TTL.as_secs() + 1, PullRequestStatus::Open);
+        assert!(!cached(1));
+
+        // Merged and closed ones can't, so they're kept forever.
+        let merged = PullRequestStatus::Merged {
+            merge_commit_oid: Some("abc".to_string()),
+        };
+        store(2, now(), merged);
+        assert!(cached(2));
+        store(3, now(), PullRequestStatus::Closed);
+        assert!(cached(3));
+
+        // Entries that can't be read are fetched again.
+        assert!(!cached(4));
+        let path = cache.path("NixOS", "nixpkgs", number(5));
+        write(&path, br#"{"fetched_at":0,"info":{"branch":"mas"#).unwrap();
+        assert!(!cached(5));
+        write(&path, b"\0\0\0\0").unwrap();
+        assert!(!cached(5));
+        store(5, 0, PullRequestStatus::Closed);
+        assert!(cached(5));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
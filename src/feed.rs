@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Atom feeds of the branches PRs have reached, for people who'd
+//! rather follow them in a feed reader than subscribe.
+//!
+//! Feeds are made from what updates record, so the PRs in a feed are
+//! updated along with the ones people are subscribed to, for as long
+//! as the feed is being read.
+
+use std::collections::BTreeSet;
+use std::time::SystemTime;
+
+use askama::Template;
+use humantime::format_rfc3339_seconds;
+use serde::Deserialize;
+use tide::{Request, Response};
+use urlencoding::encode;
+
+use crate::project::{self, Project};
+use crate::store::{self, Reached};
+use crate::types::PrNumber;
+use crate::{github, record_progress, track, track_pr, Query, CONFIG};
+
+/// The most PRs that can be in one feed.
+const MAX_PRS: usize = 100;
+
+/// The most PRs whose feeds can be kept up to date at once, so that
+/// asking for feeds of made up PRs can't make updates go on forever.
+const MAX_WATCHED: usize = 10_000;
+
+/// The most entries to show in one feed.
+const MAX_ENTRIES: usize = 100;
+
+struct Entry {
+    id: String,
+    title: String,
+    link: String,
+    hydra_link: Option<String>,
+    updated: String,
+    summary: String,
+}
+
+impl Entry {
+    fn new(project: &Project, reached: Reached) -> Self {
+        let link = page_link(project, reached.pr);
+        Self {
+            // The same in every feed the entry is in, so that readers
+            // subscribed to more than one only show it once.
+            id: format!("{}#{}", link, encode(&reached.branch.name)),
+            title: format!("#{} reached {}", reached.pr, reached.branch.name),
+            link,
+            hydra_link: reached.branch.hydra_link,
+            updated: format_rfc3339_seconds(reached.reached_at).to_string(),
+            summary: format!(
+                "{} PR #{} (\"{}\") reached {}.",
+                project.title(),
+                reached.pr,
+                reached.pr_title,
+                reached.branch.name
+            ),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "feed.xml")]
+struct FeedTemplate {
+    id: String,
+    title: String,
+    link: String,
+    updated: String,
+    entries: Vec<Entry>,
+}
+
+impl FeedTemplate {
+    fn new(
+        project: &Project,
+        id: String,
+        title: String,
+        link: String,
+        reached: Vec<Reached>,
+    ) -> Self {
+        let updated = reached
+            .first()
+            .map_or_else(SystemTime::now, |reached| reached.reached_at);
+        Self {
+            id,
+            title,
+            link,
+            updated: format_rfc3339_seconds(updated).to_string(),
+            entries: reached
+                .into_iter()
+                .map(|reached| Entry::new(project, reached))
+                .collect(),
+        }
+    }
+
+    fn into_response(self) -> http_types::Result<Response> {
+        Ok(Response::builder(200)
+            .content_type("application/atom+xml")
+            .body(self.render()?)
+            .build())
+    }
+}
+
+fn error_response(status: u16, message: impl ToString) -> http_types::Result<Response> {
+    Ok(Response::builder(status).body(message.to_string()).build())
+}
+
+/// The page for a PR.
+fn page_link(project: &Project, pr: PrNumber) -> String {
+    format!("{}/?repo={}&pr={}", CONFIG.url, project.name, pr)
+}
+
+/// The feed for a single PR.
+pub fn pr_link(project: &Project, pr: PrNumber) -> String {
+    format!("{}/feed/pr/{}.atom?repo={}", CONFIG.url, pr, project.name)
+}
+
+/// A feed with an entry for each branch a PR has reached.
+pub async fn pr<S>(request: Request<S>) -> http_types::Result<Response> {
+    let Query { repo, .. } = request.query()?;
+    let file = request.param("file")?;
+
+    let Some(project) = project::find(repo.as_deref()) else {
+        let repo = repo.unwrap_or_default();
+        return error_response(404, format!("No such project: {}.", repo));
+    };
+
+    let Some(number) = file.strip_suffix(".atom") else {
+        return error_response(404, format!("No such feed: {}.", file));
+    };
+    let number = match number.parse() {
+        Ok(number) => number,
+        Err(e) => return error_response(400, e),
+    };
+
+    let pr = match track_pr(project, number).await {
+        Ok(pr) => pr,
+        Err(e) => return error_response(e.status(), e),
+    };
+    let Some(ref tree) = pr.tree else {
+        return error_response(410, format!("PR #{} was closed.", number));
+    };
+
+    let store = store::store();
+    if !store.watch(&project.name, number, MAX_WATCHED)? {
+        return error_response(503, "Too many feeds are being followed.  Try again later.");
+    }
+    record_progress(project, &pr, tree)?;
+
+    let title = format!("{} PR #{} (\"{}\")", project.title(), number, pr.title);
+    let reached = store.reached(&project.name, &[number], MAX_ENTRIES)?;
+    FeedTemplate::new(
+        project,
+        pr_link(project, number),
+        title,
+        page_link(project, number),
+        reached,
+    )
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct PrsQuery {
+    repo: Option<String>,
+    /// Comma separated PR numbers.
+    prs: String,
+}
+
+/// A feed with an entry for each branch any of a list of PRs has
+/// reached.
+pub async fn prs<S>(request: Request<S>) -> http_types::Result<Response> {
+    let PrsQuery { repo, prs } = request.query()?;
+
+    let Some(project) = project::find(repo.as_deref()) else {
+        let repo = repo.unwrap_or_default();
+        return error_response(404, format!("No such project: {}.", repo));
+    };
+
+    let numbers = prs
+        .split(',')
+        .filter(|number| !number.is_empty())
+        .map(str::parse)
+        .collect::<Result<BTreeSet<PrNumber>, _>>();
+    let numbers: Vec<_> = match numbers {
+        Ok(numbers) if numbers.is_empty() => return error_response(400, "No PRs given."),
+        Ok(numbers) if numbers.len() > MAX_PRS => {
+            return error_response(400, format!("Feeds can't have more than {} PRs.", MAX_PRS))
+        }
+        Ok(numbers) => numbers.into_iter().collect(),
+        Err(e) => return error_response(400, e),
+    };
+
+    // PRs that were already in a feed are kept up to date by updates,
+    // but new ones are looked up now, so that they aren't missing
+    // until the next update, and only kept up to date if they exist.
+    let store = store::store();
+    let mut new = Vec::new();
+    for &number in &numbers {
+        if !store.read(&project.name, number)? {
+            new.push(number);
+        }
+    }
+    let pr_infos = github()
+        .pr_infos(
+            &project.owner,
+            &project.repo,
+            &new,
+            CONFIG.github_batch_size,
+        )
+        .await;
+    for (number, pr_info) in pr_infos {
+        match pr_info {
+            Ok(pr_info) => {
+                let pr = track(project, number, pr_info).await;
+                let Some(ref tree) = pr.tree else {
+                    continue;
+                };
+                if !store.watch(&project.name, number, MAX_WATCHED)? {
+                    return error_response(
+                        503,
+                        "Too many feeds are being followed.  Try again later.",
+                    );
+                }
+                record_progress(project, &pr, tree)?;
+            }
+            Err(e) => eprintln!("pr-tracker: {}#{}: {}", project.name, number, e),
+        }
+    }
+
+    let list = numbers
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let id = format!(
+        "{}/feed/prs.atom?repo={}&prs={}",
+        CONFIG.url, project.name, list
+    );
+    let title = format!(
+        "{} PRs {}",
+        project.title(),
+        numbers
+            .iter()
+            .map(|number| format!("#{}", number))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let reached = store.reached(&project.name, &numbers, MAX_ENTRIES)?;
+    FeedTemplate::new(project, id, title, format!("{}/", CONFIG.url), reached).into_response()
+}
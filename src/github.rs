@@ -2,15 +2,23 @@
 // SPDX-FileCopyrightText: 2021 Alyssa Ross <hi@alyssa.is>
 // SPDX-FileCopyrightText: 2021 Sumner Evans <me@sumnerevans.com>
 
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
 use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
 
 use graphql_client::GraphQLQuery;
-use serde::Deserialize;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use surf::http::headers::HeaderValue;
 use surf::StatusCode;
 
+use crate::types::{Filter, PrNumber};
+
 // ISO 8601 dates can be compared chronologically simply by comparing
 // them lexicographically, so representing them as strings and
 // comparing them as strings works just fine.  (As long as GitHub
@@ -22,10 +30,15 @@ type GitObjectID = String;
 #[derive(Debug)]
 pub enum Error {
     NotFound,
+    /// A request for several PRs at once failed, so the error applies
+    /// to each of them.
+    Batch(Arc<Error>),
     Serialization(serde_json::Error),
     Request(surf::Error),
     Response(StatusCode),
     Deserialization(http_types::Error),
+    /// GitHub answered, but with errors instead of what was asked for.
+    GraphQL(Vec<String>),
 }
 
 impl Display for Error {
@@ -33,10 +46,12 @@ impl Display for Error {
         use Error::*;
         match self {
             NotFound => write!(f, "Not found"),
+            Batch(e) => write!(f, "{}", e),
             Serialization(e) => write!(f, "Serialization error: {}", e),
             Request(e) => write!(f, "Request error: {}", e),
             Response(s) => write!(f, "Unexpected response status: {}", s),
             Deserialization(e) => write!(f, "Deserialization error: {}", e),
+            GraphQL(messages) => write!(f, "GitHub: {}", messages.join("; ")),
         }
     }
 }
@@ -69,7 +84,7 @@ const FIRST_KNOWN_NULL_MERGE_COMMIT: &str = "2013-10-20T15:50:06Z";
 )]
 struct PrInfoQuery;
 
-type PullRequest = pr_info_query::PrInfoQueryRepositoryPullRequest;
+type PullRequest = pr_info_query::PrInfoFields;
 
 impl PullRequest {
     fn merge_commit_oid(&self) -> Option<&str> {
@@ -81,12 +96,187 @@ impl PullRequest {
     }
 }
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "vendor/github_schema.graphql",
+    query_path = "src/search_prs.graphql",
+    response_derives = "Debug"
+)]
+struct SearchPrsQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "vendor/github_schema.graphql",
+    query_path = "src/backports.graphql",
+    response_derives = "Debug"
+)]
+struct BackportsQuery;
+
+/// The line `git cherry-pick -x` adds to the commits it makes.
+static CHERRY_PICKED: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\(cherry picked from commit ([0-9a-f]{40})\)").unwrap());
+
+/// A PR that mentions another, and might be a backport of it.
+#[derive(Debug)]
+struct Candidate {
+    number: i64,
+    branch: String,
+    /// The owner and name of the repository it's in.
+    repository: String,
+    /// The messages of its commits.
+    messages: Vec<String>,
+}
+
+/// Picks out the backports of a PR in `repository` from the PRs that
+/// mention it.  A PR is a backport if it's in the same repository, and
+/// either was opened against a branch the original is labelled to be
+/// backported to, like `backport release-24.05`, or cherry-picks one of
+/// the original's `commits`.
+fn pick_backports(
+    repository: &str,
+    labels: &[String],
+    commits: &HashSet<String>,
+    candidates: Vec<Candidate>,
+) -> Vec<PrNumber> {
+    let branches: HashSet<&str> = labels
+        .iter()
+        .filter_map(|label| label.strip_prefix("backport "))
+        .collect();
+
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.repository.eq_ignore_ascii_case(repository))
+        .filter(|candidate| {
+            branches.contains(&*candidate.branch)
+                || candidate.messages.iter().any(|message| {
+                    CHERRY_PICKED
+                        .captures_iter(message)
+                        .any(|captures| commits.contains(&captures[1]))
+                })
+        })
+        .filter_map(|candidate| candidate.number.try_into().ok())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// GitHub's search stops at this many results, however many pages
+/// they're split into.
+const MAX_SEARCH_RESULTS: usize = 1000;
+
+impl From<PullRequest> for PrInfo {
+    fn from(pr: PullRequest) -> Self {
+        let status = if pr.merged {
+            let merge_commit_oid = pr.merge_commit_oid().map(Into::into);
+            PullRequestStatus::Merged { merge_commit_oid }
+        } else if pr.closed {
+            PullRequestStatus::Closed
+        } else {
+            PullRequestStatus::Open
+        };
+
+        PrInfo {
+            branch: pr.base_ref_name,
+            title: pr.title,
+            status,
+        }
+    }
+}
+
+/// Builds a query for many PRs in the same repository at once, using
+/// an alias for each PR.  The result has the same shape as
+/// [`BatchResponseData`].
+fn batch_query(prs: &[PrNumber]) -> String {
+    const QUERY: &str = include_str!("pr_info.graphql");
+    let fragment = &QUERY[QUERY.find("fragment PrInfoFields").unwrap()..];
+
+    let mut query =
+        "query PrInfoBatchQuery($owner: String!, $repo: String!) {\n  repository(owner: $owner, name: $repo) {\n"
+            .to_string();
+    for pr in prs {
+        query += &format!("    pr{pr}: pullRequest(number: {pr}) {{ ...PrInfoFields }}\n");
+    }
+    query += "  }\n}\n\n";
+    query += fragment;
+    query
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponseData {
+    repository: Option<HashMap<String, Option<PullRequest>>>,
+}
+
 #[derive(Debug, Deserialize)]
 struct GitHubGraphQLResponse<D> {
-    data: D,
+    data: Option<D>,
+    /// Errors can come with data, for the parts that couldn't be
+    /// looked up, or instead of it, like when we're rate limited.
+    #[serde(default)]
+    errors: Vec<GraphQLError>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+struct GraphQLError {
+    message: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// The field in the data that the error is about.
+    #[serde(default)]
+    path: Vec<serde_json::Value>,
+}
+
+/// Explains why something asked for is missing from a response.  Only
+/// things GitHub says don't exist, or that are missing without any
+/// errors, are [`Error::NotFound`].
+fn missing<'e>(errors: impl IntoIterator<Item = &'e GraphQLError>) -> Error {
+    let messages: Vec<_> = errors
+        .into_iter()
+        .filter(|e| e.kind.as_deref() != Some("NOT_FOUND"))
+        .map(|e| e.message.clone())
+        .collect();
+    if messages.is_empty() {
+        Error::NotFound
+    } else {
+        Error::GraphQL(messages)
+    }
+}
+
+/// Sorts out the results for each PR in `batch` from a response,
+/// where `prN` is the PR numbered N.  Only PRs that are explicitly
+/// null are [`Error::NotFound`]; if the whole response or repository is
+/// missing, the error applies to the whole batch.
+fn batch_results(
+    batch: &[PrNumber],
+    response: GitHubGraphQLResponse<BatchResponseData>,
+) -> HashMap<PrNumber, Result<PrInfo, Error>> {
+    let errors = response.errors;
+    let Some(mut prs) = response.data.and_then(|data| data.repository) else {
+        let e = Arc::new(missing(&errors));
+        return batch
+            .iter()
+            .map(|pr| (*pr, Err(Error::Batch(e.clone()))))
+            .collect();
+    };
+
+    batch
+        .iter()
+        .map(|pr| {
+            let alias = format!("pr{}", pr);
+            let info = match prs.remove(&alias) {
+                Some(Some(info)) => Ok(info.into()),
+                Some(None) => {
+                    Err(missing(errors.iter().filter(|e| {
+                        e.path.last().and_then(|p| p.as_str()) == Some(&alias)
+                    })))
+                }
+                None => Err(Error::Batch(Arc::new(missing(&errors)))),
+            };
+            (*pr, info)
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum PullRequestStatus {
     Open,
     Closed,
@@ -97,13 +287,33 @@ pub enum PullRequestStatus {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PrInfo {
     pub branch: String,
     pub title: String,
     pub status: PullRequestStatus,
 }
 
+/// A PR that a search found.
+#[derive(Debug)]
+pub struct FoundPr {
+    pub number: PrNumber,
+    /// When it was merged, if it's been merged.
+    pub merged_at: Option<DateTime>,
+    /// Whether it was closed without being merged.
+    pub closed: bool,
+    /// When it last changed, which searches are sorted by.
+    pub updated_at: DateTime,
+}
+
+/// What a search found, least recently updated first.
+#[derive(Debug)]
+pub struct Found {
+    pub prs: Vec<FoundPr>,
+    /// Whether there were more than GitHub would return.
+    pub truncated: bool,
+}
+
 pub struct GitHub<'a> {
     token: &'a OsStr,
     user_agent: &'a OsStr,
@@ -120,14 +330,11 @@ impl<'a> GitHub<'a> {
         HeaderValue::from_bytes(value)
     }
 
-    pub async fn pr_info_for_nixpkgs_pr(&self, pr: i64) -> Result<PrInfo, Error> {
-        let query = PrInfoQuery::build_query(pr_info_query::Variables {
-            owner: "NixOS".to_string(),
-            repo: "nixpkgs".to_string(),
-            number: pr,
-        });
-
-        let response = surf::post("https://api.github.com/graphql")
+    async fn query<D: DeserializeOwned>(
+        &self,
+        query: &impl Serialize,
+    ) -> Result<GitHubGraphQLResponse<D>, Error> {
+        let mut response = surf::post("https://api.github.com/graphql")
             .header("Accept", "application/vnd.github.merge-info-preview+json")
             .header(
                 "User-Agent",
@@ -138,7 +345,7 @@ impl<'a> GitHub<'a> {
                 "Authorization",
                 self.authorization_header().map_err(Error::Request)?,
             )
-            .body(serde_json::to_vec(&query).map_err(Error::Serialization)?)
+            .body(serde_json::to_vec(query).map_err(Error::Serialization)?)
             .send()
             .await
             .map_err(Error::Request)?;
@@ -150,30 +357,318 @@ impl<'a> GitHub<'a> {
             return Err(Error::Response(status));
         }
 
-        let data: GitHubGraphQLResponse<pr_info_query::ResponseData> = dbg!(response)
-            .body_json()
-            .await
-            .map_err(Error::Deserialization)?;
+        response.body_json().await.map_err(Error::Deserialization)
+    }
 
-        let pr = data
+    pub async fn pr_info(&self, owner: &str, repo: &str, pr: PrNumber) -> Result<PrInfo, Error> {
+        let query = PrInfoQuery::build_query(pr_info_query::Variables {
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            number: pr.get(),
+        });
+
+        let response: GitHubGraphQLResponse<pr_info_query::ResponseData> =
+            self.query(&query).await?;
+        let pr = response
             .data
-            .repository
+            .and_then(|data| data.repository)
             .and_then(|repo| repo.pull_request)
-            .ok_or(Error::NotFound)?;
+            .ok_or_else(|| missing(&response.errors))?;
 
-        let status = if pr.merged {
-            let merge_commit_oid = pr.merge_commit_oid().map(Into::into);
-            PullRequestStatus::Merged { merge_commit_oid }
-        } else if pr.closed {
-            PullRequestStatus::Closed
-        } else {
-            PullRequestStatus::Open
+        Ok(pr.into())
+    }
+
+    /// Looks up many PRs in the same repository, using one request for
+    /// each `batch_size` PRs.
+    pub async fn pr_infos(
+        &self,
+        owner: &str,
+        repo: &str,
+        prs: &[PrNumber],
+        batch_size: usize,
+    ) -> HashMap<PrNumber, Result<PrInfo, Error>> {
+        let mut results = HashMap::new();
+
+        for batch in prs.chunks(batch_size.max(1)) {
+            let query = json!({
+                "query": batch_query(batch),
+                "variables": { "owner": owner, "repo": repo },
+            });
+
+            match self.query(&query).await {
+                Ok(response) => results.extend(batch_results(batch, response)),
+
+                Err(e) => {
+                    let e = Arc::new(e);
+                    for pr in batch {
+                        results.insert(*pr, Err(Error::Batch(e.clone())));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    /// Finds the PRs that backport `pr` to other branches, in number
+    /// order.
+    pub async fn backports(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr: PrNumber,
+    ) -> Result<Vec<PrNumber>, Error> {
+        use backports_query::BackportsQueryRepositoryPullRequestTimelineItemsNodes as Node;
+        use backports_query::BackportsQueryRepositoryPullRequestTimelineItemsNodesOnCrossReferencedEventSource as Source;
+
+        let query = BackportsQuery::build_query(backports_query::Variables {
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            number: pr.get(),
+        });
+        let response: GitHubGraphQLResponse<backports_query::ResponseData> =
+            self.query(&query).await?;
+        let pr = response
+            .data
+            .and_then(|data| data.repository)
+            .and_then(|repo| repo.pull_request)
+            .ok_or_else(|| missing(&response.errors))?;
+
+        let labels: Vec<String> = pr
+            .labels
+            .and_then(|labels| labels.nodes)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|label| label.name)
+            .collect();
+        // Backports can cherry-pick either the PR's own commits or the
+        // commit that merged it.
+        let commits: HashSet<String> = pr
+            .commits
+            .nodes
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|node| node.commit.oid)
+            .chain(pr.merge_commit.map(|commit| commit.oid))
+            .collect();
+        let candidates = pr
+            .timeline_items
+            .nodes
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|node| match node {
+                Node::CrossReferencedEvent(event) => match event.source {
+                    Source::PullRequest(source) => Some(Candidate {
+                        number: source.number,
+                        branch: source.base_ref_name,
+                        repository: source.repository.name_with_owner,
+                        messages: source
+                            .commits
+                            .nodes
+                            .into_iter()
+                            .flatten()
+                            .flatten()
+                            .map(|node| node.commit.message)
+                            .collect(),
+                    }),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+
+        Ok(pick_backports(
+            &format!("{}/{}", owner, repo),
+            &labels,
+            &commits,
+            candidates,
+        ))
+    }
+
+    /// Finds the PRs in a repository that match `filter`, and have been
+    /// updated since `since`, an ISO 8601 date.
+    pub async fn search_prs(
+        &self,
+        owner: &str,
+        repo: &str,
+        filter: &Filter,
+        since: &str,
+    ) -> Result<Found, Error> {
+        use search_prs_query::SearchPrsQuerySearchNodes as Node;
+
+        let query = format!(
+            "repo:{}/{} is:pr {} updated:>={} sort:updated-asc",
+            owner,
+            repo,
+            filter.qualifier(),
+            since
+        );
+        let mut found = Vec::new();
+        let mut after = None;
+
+        loop {
+            let query = SearchPrsQuery::build_query(search_prs_query::Variables {
+                query: query.clone(),
+                after,
+            });
+            let response: GitHubGraphQLResponse<search_prs_query::ResponseData> =
+                self.query(&query).await?;
+            // A search is only worth anything if it's complete.
+            let data = match response.data {
+                Some(data) if response.errors.is_empty() => data,
+                _ => return Err(missing(&response.errors)),
+            };
+
+            for node in data.search.nodes.into_iter().flatten().flatten() {
+                let Node::PullRequest(pr) = node else {
+                    continue;
+                };
+                let Ok(number) = pr.number.try_into() else {
+                    continue;
+                };
+                found.push(FoundPr {
+                    number,
+                    merged_at: pr.merged_at.filter(|_| pr.merged),
+                    closed: pr.closed && !pr.merged,
+                    updated_at: pr.updated_at,
+                });
+            }
+
+            let page_info = data.search.page_info;
+            if !page_info.has_next_page {
+                return Ok(Found {
+                    prs: found,
+                    truncated: false,
+                });
+            }
+            if found.len() >= MAX_SEARCH_RESULTS {
+                return Ok(Found {
+                    prs: found,
+                    truncated: true,
+                });
+            }
+            after = page_info.end_cursor;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_query_aliases() {
+        let query = batch_query(&[1.try_into().unwrap(), 23.try_into().unwrap()]);
+        assert!(query.contains("pr1: pullRequest(number: 1) { ...PrInfoFields }"));
+        assert!(query.contains("pr23: pullRequest(number: 23) { ...PrInfoFields }"));
+        assert!(query.contains("fragment PrInfoFields on PullRequest {"));
+        assert!(!query.contains("query PrInfoQuery"));
+    }
+
+    #[test]
+    fn backports() {
+        let oid = "0123456789abcdef0123456789abcdef01234567";
+        let candidate = |number, branch: &str, repository: &str, message: &str| Candidate {
+            number,
+            branch: branch.to_string(),
+            repository: repository.to_string(),
+            messages: vec![message.to_string()],
         };
+        let candidates = vec![
+            candidate(4, "release-24.05", "NixOS/nixpkgs", "hello: fix"),
+            candidate(
+                3,
+                "release-23.11",
+                "NixOS/nixpkgs",
+                &format!("hello: fix\n\n(cherry picked from commit {})", oid),
+            ),
+            candidate(2, "master", "NixOS/nixpkgs", "Mentions the fix"),
+            candidate(5, "release-24.05", "someone/nixpkgs", "hello: fix"),
+            candidate(4, "release-24.05", "NixOS/nixpkgs", "hello: fix"),
+        ];
 
-        Ok(PrInfo {
-            branch: pr.base_ref_name,
-            title: pr.title,
-            status,
-        })
+        let labels = [
+            "backport release-24.05".to_string(),
+            "6.topic: hello".to_string(),
+        ];
+        let commits = HashSet::from([oid.to_string()]);
+        let numbers: Vec<_> = pick_backports("nixos/nixpkgs", &labels, &commits, candidates)
+            .into_iter()
+            .map(PrNumber::get)
+            .collect();
+        assert_eq!(numbers, [3, 4]);
+    }
+
+    #[test]
+    fn batch_response() {
+        let response = r#"{
+            "data": {
+                "repository": {
+                    "pr1": {
+                        "title": "hello",
+                        "baseRefName": "master",
+                        "mergeCommit": null,
+                        "merged": false,
+                        "mergedAt": null,
+                        "closed": false
+                    },
+                    "pr2": null
+                }
+            }
+        }"#;
+        let response = serde_json::from_str(response).unwrap();
+        let batch = [1.try_into().unwrap(), 2.try_into().unwrap()];
+        let mut results = batch_results(&batch, response);
+        let pr1 = results.remove(&batch[0]).unwrap().unwrap();
+        assert_eq!(pr1.title, "hello");
+        assert!(matches!(pr1.status, PullRequestStatus::Open));
+        assert!(matches!(
+            results.remove(&batch[1]),
+            Some(Err(Error::NotFound))
+        ));
+    }
+
+    #[test]
+    fn batch_errors() {
+        let batch = [1.try_into().unwrap(), 2.try_into().unwrap()];
+        let results = |response| batch_results(&batch, serde_json::from_str(response).unwrap());
+
+        // Being rate limited says nothing about whether PRs exist.
+        let limited = results(
+            r#"{
+                "data": null,
+                "errors": [{ "type": "RATE_LIMITED", "message": "API rate limit exceeded" }]
+            }"#,
+        );
+        for pr in &batch {
+            assert!(
+                matches!(&limited[pr], Err(Error::Batch(e)) if matches!(**e, Error::GraphQL(_)))
+            );
+        }
+
+        let no_repository = results(
+            r#"{
+                "data": { "repository": null },
+                "errors": [{ "type": "FORBIDDEN", "message": "no", "path": ["repository"] }]
+            }"#,
+        );
+        for pr in &batch {
+            assert!(matches!(&no_repository[pr], Err(Error::Batch(_))));
+        }
+
+        let partial = results(
+            r#"{
+                "data": { "repository": { "pr1": null, "pr2": null } },
+                "errors": [
+                    { "type": "NOT_FOUND", "message": "gone", "path": ["repository", "pr1"] },
+                    { "type": "TIMEOUT", "message": "slow", "path": ["repository", "pr2"] }
+                ]
+            }"#,
+        );
+        assert!(matches!(partial[&batch[0]], Err(Error::NotFound)));
+        assert!(matches!(partial[&batch[1]], Err(Error::GraphQL(_))));
     }
 }
@@ -1,76 +1,475 @@
-use std::collections::HashSet;
+use std::env;
+use std::fmt::{self, Display, Formatter};
 
-use anyhow::Result;
-use lettre::message::header::ContentType;
-use lettre::message::Mailbox;
-use lettre::transport::smtp::authentication::Credentials;
+use askama::Template;
+use async_std::task;
+use lettre::address::AddressError;
+use lettre::message::header::{Header, HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::response::{Category, Severity};
+use lettre::transport::smtp::{self, authentication::Credentials};
 use lettre::{Message, SmtpTransport, Transport};
-use std::env;
-use urlencoding::encode;
+use once_cell::sync::Lazy;
 
+use crate::notifier::{self, unsubscribe_link, unsubscribe_standing_link, Notifier, Target};
+use crate::project::{Project, DEFAULT_PROJECT};
+use crate::store::Notification;
+use crate::tree::Branch;
+use crate::types::{EmailAddress, Filter, PrNumber, Recipient};
 use crate::CONFIG;
 
-pub fn send_notification(
-    recipient: &str,
-    branches: &HashSet<String>,
-    pr_number: &str,
-    pr_title: &str,
-    last: bool,
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum Error {
+    NotConfigured,
+    Address(AddressError),
+    Template(askama::Error),
+    Message(lettre::error::Error),
+    Smtp(smtp::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            NotConfigured => write!(f, "PR_TRACKER_MAIL_PASSWD isn't set"),
+            Address(e) => write!(f, "invalid address: {}", e),
+            Template(e) => write!(f, "rendering mail: {}", e),
+            Message(e) => write!(f, "building mail: {}", e),
+            Smtp(e) => write!(f, "SMTP: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the problem is with the recipient's address, so that
+    /// trying again won't help.  Other failures, like the server being
+    /// down or rejecting our credentials, aren't the recipient's fault.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::Address(_) => true,
+            // 55x: the mailbox is unavailable, or its name isn't
+            // allowed.
+            Self::Smtp(e) => e.status().is_some_and(|code| {
+                code.severity == Severity::PermanentNegativeCompletion
+                    && code.category == Category::MailSystem
+            }),
+            _ => false,
+        }
+    }
+}
+
+impl From<AddressError> for Error {
+    fn from(e: AddressError) -> Self {
+        Self::Address(e)
+    }
+}
+
+impl From<askama::Error> for Error {
+    fn from(e: askama::Error) -> Self {
+        Self::Template(e)
+    }
+}
+
+impl From<lettre::error::Error> for Error {
+    fn from(e: lettre::error::Error) -> Self {
+        Self::Message(e)
+    }
+}
+
+impl From<smtp::Error> for Error {
+    fn from(e: smtp::Error) -> Self {
+        Self::Smtp(e)
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Sends all of our mail, keeping a pool of connections to the server
+/// open between messages.
+///
+/// This uses lettre's blocking transport, not `AsyncSmtpTransport`.
+/// Its async-std support only does TLS with rustls, which needs a newer
+/// `subtle` than the one that http-types' cookie support pins.
+pub struct Mailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl Mailer {
+    fn new(sending_passwd: String) -> Result<Self> {
+        let sending_address = &CONFIG.email_address;
+        let sending_user = CONFIG.email_user.as_ref().unwrap_or(sending_address);
+        let creds = Credentials::new(sending_user.to_string(), sending_passwd);
+
+        Ok(Self {
+            from: Mailbox::new(Some("PR-Tracker".to_string()), sending_address.parse()?),
+            transport: SmtpTransport::relay(&CONFIG.email_server)?
+                .credentials(creds)
+                .build(),
+        })
+    }
+}
+
+/// Mail is only sent if there's a password to send it with, so that
+/// deployments that only notify webhooks or Matrix don't need one.
+static MAILER: Lazy<Option<Mailer>> = Lazy::new(|| {
+    let passwd = env::var("PR_TRACKER_MAIL_PASSWD").ok()?;
+    match Mailer::new(passwd) {
+        Ok(mailer) => Some(mailer),
+        Err(e) => {
+            eprintln!("pr-tracker: mail: {}", e);
+            std::process::exit(78);
+        }
+    }
+});
+
+/// Returns what to send mail with, if mail can be sent.
+pub fn mailer() -> Option<&'static Mailer> {
+    MAILER.as_ref()
+}
+
+/// The `List-Unsubscribe` header, from RFC 2369.
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, BoxError> {
+        Ok(Self(s.trim_start_matches('<').trim_end_matches('>').into()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), format!("<{}>", self.0))
+    }
+}
+
+/// The `List-Unsubscribe-Post` header, from RFC 8058, which tells mail
+/// clients they can unsubscribe by POSTing to the `List-Unsubscribe`
+/// link, without asking the user to confirm on our site.
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(_: &str) -> Result<Self, BoxError> {
+        Ok(Self)
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".to_string())
+    }
+}
+
+/// Where to unsubscribe from notifications.
+struct UnsubscribeLinks {
+    pr: String,
+    all: String,
+}
+
+/// What a notification mail says, in either format.
+struct NotificationMail<'a> {
+    project_title: &'a str,
+    pr_number: PrNumber,
+    pr_title: &'a str,
+    pr_link: String,
+    branches: &'a [Branch],
+    /// Missing from the last notification about a PR, after which the
+    /// subscription ends anyway.
+    unsubscribe: Option<UnsubscribeLinks>,
+    /// The standing subscription that found the PR, and a link that
+    /// ends it.
+    unsubscribe_standing: Option<(&'a Filter, String)>,
+}
+
+#[derive(Template)]
+#[template(path = "mail/notification.html")]
+struct NotificationHtml<'a> {
+    mail: &'a NotificationMail<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "mail/notification.txt")]
+struct NotificationText<'a> {
+    mail: &'a NotificationMail<'a>,
+}
+
+/// A digest mail, with what each PR has reached.
+struct DigestMail<'a> {
+    prs: Vec<NotificationMail<'a>>,
+    unsubscribe_all: String,
+}
+
+#[derive(Template)]
+#[template(path = "mail/digest.html")]
+struct DigestHtml<'a> {
+    mail: &'a DigestMail<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "mail/digest.txt")]
+struct DigestText<'a> {
+    mail: &'a DigestMail<'a>,
+}
+
+struct Confirmation<'a> {
+    project_title: &'a str,
+    target: &'a Target<'a>,
+    target_link: String,
+    link: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "mail/confirmation.html")]
+struct ConfirmationHtml<'a> {
+    mail: &'a Confirmation<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "mail/confirmation.txt")]
+struct ConfirmationText<'a> {
+    mail: &'a Confirmation<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "mail/manage.html")]
+struct ManageHtml<'a> {
+    link: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "mail/manage.txt")]
+struct ManageText<'a> {
+    link: &'a str,
+}
+
+/// Identifies a PR in a subject line, leaving out the project name for
+/// the default project.
+fn subject_pr(project: &Project, pr_number: PrNumber) -> String {
+    match project.name.as_str() {
+        DEFAULT_PROJECT => format!("#{}", pr_number),
+        name => format!("{}#{}", name, pr_number),
+    }
+}
+
+fn notification_mail<'a>(
+    project: &'a Project,
+    recipient: &EmailAddress,
+    notification: &'a Notification,
+) -> NotificationMail<'a> {
+    let pr_number = notification.pr;
+    let unsubscribe = (!notification.last).then(|| {
+        let recipient = Recipient::Email(recipient.clone());
+        UnsubscribeLinks {
+            pr: unsubscribe_link(&recipient, Some((project, pr_number))),
+            all: unsubscribe_link(&recipient, None),
+        }
+    });
+    let unsubscribe_standing = notification.filter.as_ref().map(|filter| {
+        let recipient = Recipient::Email(recipient.clone());
+        (
+            filter,
+            unsubscribe_standing_link(&recipient, project, filter),
+        )
+    });
+    NotificationMail {
+        project_title: project.title(),
+        pr_number,
+        pr_title: &notification.pr_title,
+        pr_link: project.pull_link(pr_number),
+        branches: &notification.branches,
+        unsubscribe,
+        unsubscribe_standing,
+    }
+}
+
+/// Tells `recipient` about the PR in `notification`, which belongs to
+/// `project`.
+async fn send_notification(
+    project: &Project,
+    recipient: &EmailAddress,
+    notification: &Notification,
 ) -> Result<()> {
-    let mut body = format!(
-        "This is your friendly neighbourhood pr-tracker.<br>
-        PR <a href=\"https://github.com/NixOS/nixpkgs/pull/{pr_number}\">#{pr_number}</a>\
-        (\"{pr_title}\") has reached:<br>
-        {:#?}<br>",
-        branches
+    let pr_number = notification.pr;
+    let mail = notification_mail(project, recipient, notification);
+
+    let names: Vec<&str> = mail.branches.iter().map(|b| b.name.as_str()).collect();
+    let subject = format!(
+        "PR-tracker: {} ({}) has reached {}",
+        subject_pr(project, pr_number),
+        notification.pr_title,
+        names.join(", ")
     );
-    if last {
-        body += "This is the last update you will get for this pr.<br>\
-        Thx for using this service<br>\
-        Goodbye";
-    } else {
-        body += &format!(
-            "<a href=\"{}/unsubscribe?pr={pr_number}&email={}\">Unsubscribe from this PR</a><br>",
-            &CONFIG.url,
-            encode(recipient)
-        );
-        body += &format!(
-            "<a href=\"{}/unsubscribe?email={}\">Unsubscribe from all PRs</a>",
-            &CONFIG.url,
-            encode(recipient)
-        );
-    }
-    let sending_address = &CONFIG.email_address;
-    let sending_user = match &CONFIG.email_user {
-        Some(address) => address,
-        _ => &sending_address,
+
+    let text = NotificationText { mail: &mail }.render()?;
+    let html = NotificationHtml { mail: &mail }.render()?;
+    let list_unsubscribe = mail.unsubscribe.map(|links| links.pr);
+    send(recipient, subject, text, html, list_unsubscribe).await
+}
+
+/// Tells `recipient` about several PRs in one mail.
+async fn send_digest(
+    recipient: &EmailAddress,
+    notifications: &[(&Project, &Notification)],
+) -> Result<()> {
+    let mail = DigestMail {
+        prs: notifications
+            .iter()
+            .map(|(project, notification)| notification_mail(project, recipient, notification))
+            .collect(),
+        unsubscribe_all: unsubscribe_link(&Recipient::Email(recipient.clone()), None),
     };
-    let sending_passwd = env::var("PR_TRACKER_MAIL_PASSWD")?;
 
-    let sending_server = CONFIG.email_server.as_ref();
+    let subject = match mail.prs.len() {
+        1 => "PR-tracker: 1 PR has reached new branches".to_string(),
+        n => format!("PR-tracker: {} PRs have reached new branches", n),
+    };
+
+    let text = DigestText { mail: &mail }.render()?;
+    let html = DigestHtml { mail: &mail }.render()?;
+    let list_unsubscribe = Some(mail.unsubscribe_all);
+    send(recipient, subject, text, html, list_unsubscribe).await
+}
+
+/// Asks `recipient` to confirm that they want to be notified about a
+/// PR, by following `link`.
+async fn send_confirmation(
+    project: &Project,
+    recipient: &EmailAddress,
+    target: &Target<'_>,
+    link: &str,
+) -> Result<()> {
+    let mail = Confirmation {
+        project_title: project.title(),
+        target,
+        target_link: target.link(project),
+        link,
+    };
+
+    let subject = match target {
+        Target::Pr { number, .. } => format!(
+            "PR-tracker: {}: confirm your subscription",
+            subject_pr(project, *number)
+        ),
+        Target::Filter(filter) => format!(
+            "PR-tracker: confirm your subscription to {} PRs {}",
+            project.title(),
+            filter.description()
+        ),
+    };
+
+    let text = ConfirmationText { mail: &mail }.render()?;
+    let html = ConfirmationHtml { mail: &mail }.render()?;
+    send(recipient, subject, text, html, None).await
+}
+
+/// Sends `recipient` a link to manage their subscriptions.
+async fn send_manage_link(recipient: &EmailAddress, link: &str) -> Result<()> {
+    let subject = "PR-tracker: manage your subscriptions".to_string();
+    let text = ManageText { link }.render()?;
+    let html = ManageHtml { link }.render()?;
+    send(recipient, subject, text, html, None).await
+}
+
+/// Sends notifications by email.
+pub struct Mail;
+
+impl Notifier for Mail {
+    type Address = EmailAddress;
 
-    let email = Message::builder()
-        .from(format!("PR-Tracker <{}>", sending_address).parse().unwrap())
-        .to(Mailbox::new(None, recipient.parse().unwrap()))
-        .subject(format!(
-            "PR-tracker: {pr_number}: {pr_title} has reached {:?}",
-            branches
-        ))
-        .header(ContentType::TEXT_HTML)
-        .body(body)
-        .unwrap();
+    async fn notify(
+        &self,
+        project: &Project,
+        to: &EmailAddress,
+        notification: &Notification,
+    ) -> Result<(), notifier::Error> {
+        Ok(send_notification(project, to, notification).await?)
+    }
 
-    let creds = Credentials::new(sending_user.to_string(), sending_passwd.to_string());
+    async fn digest(
+        &self,
+        to: &EmailAddress,
+        notifications: &[(&Project, &Notification)],
+    ) -> Result<(), notifier::Error> {
+        Ok(send_digest(to, notifications).await?)
+    }
 
-    // Open a remote connection to gmail
-    let mailer = SmtpTransport::relay(&sending_server)
-        .unwrap()
-        .credentials(creds)
-        .build();
+    async fn confirm(
+        &self,
+        project: &Project,
+        to: &EmailAddress,
+        target: &Target<'_>,
+        link: &str,
+    ) -> Result<(), notifier::Error> {
+        Ok(send_confirmation(project, to, target, link).await?)
+    }
 
-    // Send the email
-    mailer.send(&email).unwrap();
+    async fn manage(&self, to: &EmailAddress, link: &str) -> Result<(), notifier::Error> {
+        Ok(send_manage_link(to, link).await?)
+    }
+}
+
+async fn send(
+    recipient: &EmailAddress,
+    subject: String,
+    text: String,
+    html: String,
+    unsubscribe: Option<String>,
+) -> Result<()> {
+    let mailer = mailer().ok_or(Error::NotConfigured)?;
+    let mut email = Message::builder()
+        .from(mailer.from.clone())
+        .to(Mailbox::new(None, recipient.as_str().parse()?))
+        .subject(subject);
+    if let Some(link) = unsubscribe {
+        email = email
+            .header(ListUnsubscribe(link))
+            .header(ListUnsubscribePost);
+    }
+    let email = email.multipart(MultiPart::alternative_plain_html(text, html))?;
 
-    println!("Email sent successfully!");
+    // SmtpTransport blocks, so keep it off the executor's threads.
+    task::spawn_blocking(move || mailer.transport.send(&email)).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notification_escapes_title_in_html_only() {
+        let branches = [Branch {
+            name: "staging".to_string(),
+            hydra_link: None,
+        }];
+        let filter = "label:security".parse().unwrap();
+        let mail = NotificationMail {
+            project_title: "Nixpkgs",
+            pr_number: "123".parse().unwrap(),
+            pr_title: "<script>alert(1)</script> & more",
+            pr_link: "https://github.com/NixOS/nixpkgs/pull/123".to_string(),
+            branches: &branches,
+            unsubscribe: None,
+            unsubscribe_standing: Some((&filter, "https://example.com/u".to_string())),
+        };
+
+        let html = NotificationHtml { mail: &mail }.render().unwrap();
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt; &amp; more"));
+        assert!(!html.contains("<script>"));
+
+        let text = NotificationText { mail: &mail }.render().unwrap();
+        assert!(text.contains("(\"<script>alert(1)</script> & more\")"));
+        assert!(text.contains("- staging\n"));
+        assert!(text.contains("matching label:security: https://example.com/u"));
+    }
+}
@@ -2,17 +2,33 @@
 // SPDX-FileCopyrightText: 2021 Alyssa Ross <hi@alyssa.is>
 // SPDX-FileCopyrightText: 2021 Sumner Evans <me@sumnerevans.com>
 
+mod api;
 mod branches;
+mod cache;
+mod feed;
 mod github;
 mod mail;
+mod manage;
+mod matrix;
 mod nixpkgs;
+mod notifier;
+mod outbox;
+mod project;
+mod reload;
+mod scheduler;
+mod standing;
+mod store;
 mod systemd;
+mod token;
 mod tree;
+mod types;
+mod webhook;
 
 use std::collections::HashSet;
-use std::fs::{remove_dir_all, remove_file, File};
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
 use std::path::PathBuf;
-use std::{ffi::OsString, fs::read_dir};
+use std::time::Duration;
 
 use askama::Template;
 use async_std::io::{self};
@@ -25,17 +41,21 @@ use async_std::process::exit;
 use clap::Parser;
 use futures_util::future::join_all;
 use http_types::mime;
+use humantime::format_rfc3339_seconds;
 use once_cell::sync::Lazy;
-use regex::Regex;
 use serde::Deserialize;
-use serde_json::json;
+use signal_hook::consts::SIGHUP;
+use signal_hook_async_std::Signals;
 use tide::{Request, Response};
 
-use github::{GitHub, PullRequestStatus};
-use mail::send_notification;
-use nixpkgs::Nixpkgs;
+use cache::Cache;
+use github::{GitHub, PrInfo, PullRequestStatus};
+use notifier::Target;
+use project::{Project, PROJECTS};
+use store::{Notification, Subscribing};
 use systemd::{is_socket_inet, is_socket_unix, listen_fds};
 use tree::Tree;
+use types::{Delivery, Filter, PrNumber, Recipient};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -53,6 +73,26 @@ pub struct Config {
     #[arg(long)]
     remote: PathBuf,
 
+    /// How often to fetch branches into local checkouts, in seconds.
+    /// 0 means only fetching when a merge commit is missing.
+    #[arg(long, default_value_t = 300)]
+    fetch_interval: u64,
+
+    /// How often to notify subscribers of PRs reaching new branches, in
+    /// seconds.  0 disables notifications.
+    #[arg(long, default_value_t = 600)]
+    update_interval: u64,
+
+    /// The most seconds to randomly add to each update interval, so
+    /// that updates don't always hit GitHub at the same time.
+    #[arg(long, default_value_t = 60)]
+    update_jitter: u64,
+
+    /// How to look up which branches contain a commit in local
+    /// checkouts.
+    #[arg(long, value_enum, default_value_t)]
+    git_backend: nixpkgs::Backend,
+
     /// The user agent to use when accessing the github API.
     #[arg(long)]
     user_agent: OsString,
@@ -61,7 +101,22 @@ pub struct Config {
     #[arg(long, default_value = "/")]
     mount: String,
 
-    /// Folder to save the subscription data into.
+    /// The SQLite database to keep subscriptions in.
+    #[arg(long, default_value = "subscriptions.sqlite")]
+    database: PathBuf,
+
+    /// How many seconds people have to confirm their subscriptions
+    /// before they're forgotten.
+    #[arg(long, default_value_t = 2 * 24 * 60 * 60)]
+    confirmation_period: u64,
+
+    /// How many seconds to keep a PR's feed up to date after it was
+    /// last read.
+    #[arg(long, default_value_t = 30 * 24 * 60 * 60)]
+    feed_retention: u64,
+
+    /// Folder that older versions saved subscriptions into.  They're
+    /// imported when the database is created.
     #[arg(long, default_value = "data")]
     data_folder: String,
 
@@ -78,26 +133,53 @@ pub struct Config {
     #[arg(long)]
     email_server: String,
 
-    /// A whitelist of allowed emails to subscribet.
+    /// How many times in a row delivering to an address can fail
+    /// because of the address, or be given up on after days of
+    /// trying, before it's disabled until the subscriber confirms a
+    /// subscription again.
+    #[arg(long, default_value_t = 5)]
+    max_delivery_failures: u32,
+
+    /// The Matrix homeserver to send notifications to Matrix IDs
+    /// through, e.g. https://matrix.org.  Matrix IDs can't subscribe
+    /// without one.
+    #[arg(long)]
+    matrix_homeserver: Option<String>,
+
+    /// A whitelist of allowed emails to subscribet, one per line.
     /// No list or an empty list disables the whitelisting, to blacklist all mails
-    /// supply a whitelist containing an invalid email.
+    /// supply a whitelist containing an email nobody uses.
     #[arg(long)]
     email_white_list: Option<PathBuf>,
+
+    /// A TOML file describing which branches each branch is merged
+    /// into, and where to find them on Hydra.
+    /// Defaults to the built-in rules for nixpkgs.
+    #[arg(long)]
+    branch_config: Option<PathBuf>,
+
+    /// A TOML file listing further GitHub repositories whose pull
+    /// requests can be tracked, alongside nixpkgs.
+    #[arg(long)]
+    projects: Option<PathBuf>,
+
+    /// Folder to cache information about PRs from GitHub in.
+    #[arg(long, default_value = "cache")]
+    cache_folder: PathBuf,
+
+    /// How many seconds information about open PRs is cached for.
+    /// Merged and closed PRs are cached forever.
+    #[arg(long, default_value_t = 300)]
+    cache_ttl: u64,
+
+    /// How many PRs to ask GitHub about in a single request when
+    /// updating subscribers.
+    #[arg(long, default_value_t = 50)]
+    github_batch_size: usize,
 }
 
 pub static CONFIG: Lazy<Config> = Lazy::new(Config::parse);
 
-static WHITE_LIST: Lazy<HashSet<String>> = Lazy::new(|| {
-    use std::io::{BufRead, BufReader};
-    match &CONFIG.email_white_list {
-        Some(str) => {
-            let reader = BufReader::new(File::open(str).unwrap());
-            reader.lines().map(|line| line.unwrap()).collect()
-        }
-        _ => HashSet::new(),
-    }
-});
-
 static GITHUB_TOKEN: Lazy<OsString> = Lazy::new(|| {
     use std::env;
 
@@ -108,187 +190,763 @@ static GITHUB_TOKEN: Lazy<OsString> = Lazy::new(|| {
 #[template(path = "page.html")]
 struct PageTemplate {
     error: Option<String>,
+    project: Option<&'static Project>,
+    repo: Option<String>,
+    projects: &'static [Project],
     pr_number: Option<String>,
+    pr_link: Option<String>,
     email: Option<String>,
+    filter: Option<String>,
+    delivery: Option<String>,
     pr_title: Option<String>,
+    feed_link: Option<String>,
+    last_fetch: Option<String>,
     closed: bool,
+    confirming: bool,
     subscribed: bool,
     tree: Option<Tree>,
+    /// The branches to tick in the tree.
+    targets: HashSet<String>,
+    backports: Vec<Backport>,
+    /// The trees of backports to branches that aren't in `tree`.
+    backport_trees: Vec<Tree>,
 }
 
-#[derive(Debug, Deserialize)]
+/// A backport of the PR shown on a page, whose tree is joined with the
+/// PR's own.
+#[derive(Debug)]
+struct Backport {
+    number: PrNumber,
+    link: String,
+    title: String,
+}
+
+impl PageTemplate {
+    fn new(repo: Option<String>) -> Self {
+        Self {
+            project: project::find(repo.as_deref()),
+            repo,
+            projects: &PROJECTS,
+            ..Default::default()
+        }
+    }
+
+    fn title(&self) -> &str {
+        self.project
+            .or_else(|| project::find(None))
+            .map(Project::title)
+            .unwrap()
+    }
+
+    /// Shows that a subscription didn't need confirming.
+    fn show_subscribing(&mut self, subscribing: Subscribing) {
+        match subscribing {
+            Subscribing::StillPending => self.confirming = true,
+            _ => self.subscribed = true,
+        }
+    }
+
+    fn show_pr(&mut self, project: &Project, mut pr: TrackedPr) {
+        self.error = pr.warning().map(String::from);
+        self.pr_link = Some(project.pull_link(pr.number));
+        self.pr_number = Some(pr.number.to_string());
+        self.feed_link = pr.tree.is_some().then(|| feed::pr_link(project, pr.number));
+        self.closed = matches!(pr.status, PullRequestStatus::Closed);
+        let has_tree = pr.tree.is_some();
+        let mut trees = Tree::union(pr.union_trees());
+        self.tree = has_tree.then(|| trees.remove(0));
+        self.backport_trees = trees;
+        self.pr_title = Some(pr.title);
+        self.backports = pr
+            .backports
+            .into_iter()
+            .map(|backport| Backport {
+                number: backport.number,
+                link: project.pull_link(backport.number),
+                title: backport.title,
+            })
+            .collect();
+        self.last_fetch = project
+            .last_fetch()
+            .map(|time| format_rfc3339_seconds(time).to_string());
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 struct Query {
+    repo: Option<String>,
     pr: Option<String>,
     email: Option<String>,
+    /// What to match PRs by, for a standing subscription.
+    filter: Option<String>,
+    /// How to hear about PRs from now on.
+    delivery: Option<String>,
+    /// The branches to subscribe to a PR reaching, if not all of them.
+    #[serde(skip)]
+    targets: Vec<String>,
 }
 
-async fn track_pr(pr_number: String, status: &mut u16, page: &mut PageTemplate) {
-    let pr_number_i64 = match pr_number.parse() {
-        Ok(n) => n,
-        Err(_) => {
-            *status = 400;
-            page.error = Some(format!("Invalid PR number: {}", pr_number));
-            return;
+impl Query {
+    /// Reads the query of a request for the page.  Each target branch
+    /// ticked on the page is its own `target` parameter, which
+    /// [`Request::query`] refuses, so this has to be done by hand.
+    fn parse<S>(request: &Request<S>) -> Self {
+        let mut query = Self::default();
+        for (key, value) in request.url().query_pairs() {
+            let value = value.into_owned();
+            match &*key {
+                "repo" => query.repo = Some(value),
+                "pr" => query.pr = Some(value),
+                "email" => query.email = Some(value),
+                "filter" => query.filter = Some(value),
+                "delivery" => query.delivery = Some(value),
+                "target" => query.targets.push(value),
+                _ => {}
+            }
         }
-    };
+        query
+    }
+}
 
-    let github = GitHub::new(&GITHUB_TOKEN, &CONFIG.user_agent);
+/// A PR, and how far it has progressed.
+struct TrackedPr {
+    number: PrNumber,
+    title: String,
+    status: PullRequestStatus,
+    /// The branches the PR has reached or will reach.  Closed PRs
+    /// won't reach any, so they don't have a tree.
+    tree: Option<Tree>,
+    /// The PRs that backport this one to other branches.  Only
+    /// [`track_pr_and_backports`] looks for them.
+    backports: Vec<TrackedPr>,
+}
+
+impl TrackedPr {
+    /// Takes the trees of the PR and its backports, the PR's first,
+    /// for [`Tree::union`].
+    fn union_trees(&mut self) -> Vec<Tree> {
+        self.tree
+            .take()
+            .into_iter()
+            .chain(
+                self.backports
+                    .iter_mut()
+                    .filter_map(|backport| backport.tree.take()),
+            )
+            .collect()
+    }
 
-    let pr_info = match github.pr_info_for_nixpkgs_pr(pr_number_i64).await {
-        Err(github::Error::NotFound) => {
-            *status = 404;
-            page.error = Some(format!("No such nixpkgs PR #{}.", pr_number_i64));
-            return;
+    fn merge_commit_oid(&self) -> Option<&str> {
+        match &self.status {
+            PullRequestStatus::Merged { merge_commit_oid } => merge_commit_oid.as_deref(),
+            _ => None,
         }
+    }
 
-        Err(e) => {
-            *status = 500;
-            page.error = Some(e.to_string());
-            return;
+    /// Explains why the tree might be incomplete.
+    fn warning(&self) -> Option<&'static str> {
+        match self.status {
+            PullRequestStatus::Merged {
+                merge_commit_oid: None,
+            } => Some("For older PRs, GitHub doesn't tell us the merge commit, so we're unable to track this PR past being merged."),
+            _ => None,
         }
+    }
+}
 
-        Ok(info) => info,
-    };
+#[derive(Debug)]
+enum TrackError {
+    NotFound {
+        owner: String,
+        repo: String,
+        number: PrNumber,
+    },
+    GitHub(github::Error),
+}
+
+impl Display for TrackError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use TrackError::*;
+        match self {
+            NotFound {
+                owner,
+                repo,
+                number,
+            } => write!(f, "No such {}/{} PR #{}.", owner, repo, number),
+            GitHub(e) => write!(f, "{}", e),
+        }
+    }
+}
 
-    page.pr_number = Some(pr_number);
-    page.pr_title = Some(pr_info.title);
+impl TrackError {
+    fn github(project: &Project, number: PrNumber, e: github::Error) -> Self {
+        match e {
+            github::Error::NotFound => TrackError::NotFound {
+                owner: project.owner.clone(),
+                repo: project.repo.clone(),
+                number,
+            },
+            e => TrackError::GitHub(e),
+        }
+    }
 
-    if matches!(pr_info.status, PullRequestStatus::Closed) {
-        page.closed = true;
-        return;
+    fn status(&self) -> u16 {
+        use TrackError::*;
+        match self {
+            NotFound { .. } => 404,
+            GitHub(_) => 502,
+        }
     }
+}
+
+fn github() -> Cache<'static> {
+    Cache::new(
+        GitHub::new(&GITHUB_TOKEN, &CONFIG.user_agent),
+        &CONFIG.cache_folder,
+        Duration::from_secs(CONFIG.cache_ttl),
+    )
+}
 
-    let nixpkgs = Nixpkgs::new(&CONFIG.path, &CONFIG.remote);
-    let tree = Tree::make(pr_info.branch.to_string(), &pr_info.status, &nixpkgs).await;
+async fn track_pr(project: &Project, number: PrNumber) -> Result<TrackedPr, TrackError> {
+    let pr_info = github()
+        .pr_info(&project.owner, &project.repo, number)
+        .await
+        .map_err(|e| TrackError::github(project, number, e))?;
+
+    Ok(track(project, number, pr_info).await)
+}
+
+/// Like [`track_pr`], but also finds the PR's backports, which only
+/// the page and the API show.
+async fn track_pr_and_backports(
+    project: &Project,
+    number: PrNumber,
+) -> Result<TrackedPr, TrackError> {
+    let mut pr = track_pr(project, number).await?;
+    pr.backports = track_backports(project, number).await;
+    Ok(pr)
+}
 
-    if let github::PullRequestStatus::Merged {
-        merge_commit_oid, ..
-    } = pr_info.status
+/// Finds the backports of a PR, and how far each has progressed.
+/// Backports closed without being merged are left out, since they'll
+/// never reach any branch.  The PR is worth showing without its
+/// backports, so failing to find them is only logged.
+async fn track_backports(project: &Project, number: PrNumber) -> Vec<TrackedPr> {
+    let numbers = match github()
+        .backports(&project.owner, &project.repo, number)
+        .await
     {
-        if merge_commit_oid.is_none() {
-            page.error = Some("For older PRs, GitHub doesn't tell us the merge commit, so we're unable to track this PR past being merged.".to_string());
+        Ok(numbers) => numbers,
+        Err(e) => {
+            eprintln!(
+                "pr-tracker: {}#{}: finding backports: {}",
+                project.name, number, e
+            );
+            return Vec::new();
         }
-    }
+    };
 
-    page.tree = Some(tree);
+    let mut pr_infos = github()
+        .pr_infos(
+            &project.owner,
+            &project.repo,
+            &numbers,
+            CONFIG.github_batch_size,
+        )
+        .await;
+    let mut backports = Vec::new();
+    for number in numbers {
+        match pr_infos.remove(&number) {
+            Some(Ok(pr_info)) => {
+                let backport = track(project, number, pr_info).await;
+                if backport.tree.is_some() {
+                    backports.push(backport);
+                }
+            }
+            Some(Err(e)) => eprintln!("pr-tracker: {}#{}: {}", project.name, number, e),
+            None => {}
+        }
+    }
+    backports
 }
-async fn update_subscribers<S>(_request: Request<S>) -> http_types::Result<Response> {
-    let mut status = 200;
-    let mut page = PageTemplate {
-        ..Default::default()
+
+/// Works out how far a PR has progressed, given what GitHub told us
+/// about it.
+async fn track(project: &Project, number: PrNumber, pr_info: PrInfo) -> TrackedPr {
+    let tree = match pr_info.status {
+        PullRequestStatus::Closed => None,
+        _ => Some(
+            Tree::make(
+                pr_info.branch.to_string(),
+                &pr_info.status,
+                &project.checkout(),
+                reload::rules().branches(&project.name),
+            )
+            .await,
+        ),
     };
 
-    let re_pull = Regex::new(r"^[0-9]*$")?;
-    let re_mail = Regex::new(
-        r#"^(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])$"#,
+    TrackedPr {
+        number,
+        title: pr_info.title,
+        status: pr_info.status,
+        tree,
+        backports: Vec::new(),
+    }
+}
+
+/// Records the branches a PR has reached, for its feed.  Returns them,
+/// and whether there are any left for it to reach.
+fn record_progress(
+    project: &Project,
+    pr: &TrackedPr,
+    tree: &Tree,
+) -> store::Result<(HashSet<String>, bool)> {
+    let mut v = Vec::new();
+    let remaining = tree.collect_branches(&mut v);
+    let current: HashSet<String> = v.into_iter().collect();
+    store::store().record_reached(
+        &project.name,
+        pr.number,
+        &pr.title,
+        &tree.branches(&current),
+        !remaining,
     )?;
-    for f in read_dir(CONFIG.data_folder.clone())? {
-        let dir_path = f?.path();
-        let dir_name = dir_path.file_name().and_then(|x| x.to_str()).unwrap();
-        if dir_path.is_dir() && re_pull.is_match(dir_name) {
-            track_pr(dir_name.to_string(), &mut status, &mut page).await;
-            println!("Pruning pr number {dir_name}");
-            if let Some(ref tree) = page.tree {
-                let mut v = Vec::new();
-                let remaining = tree.collect_branches(&mut v);
-                let current: HashSet<String> = v.into_iter().collect();
-                println!("the pr is merged in: {:#?}", current);
-                for f in read_dir(dir_path.clone())? {
-                    let file_path = f?.path();
-                    let file_name = file_path
-                        .file_name()
-                        .and_then(|x| x.to_str())
-                        .unwrap()
-                        .to_owned();
-                    if file_path.is_file() && re_mail.is_match(&file_name) {
-                        println!("{} has received notifications for:", file_name);
-                        let str = std::fs::read(file_path.clone())?;
-                        let val: HashSet<String> = serde_json::from_slice(&str)?;
-                        println!("{:#?}", val);
-                        let to_do = &current - &val;
-                        println!("They will be notified for: {:#?}", to_do);
-                        if !to_do.is_empty() {
-                            send_notification(
-                                &file_name,
-                                &to_do,
-                                page.pr_number.as_ref().unwrap(),
-                                page.pr_title.as_ref().unwrap(),
-                                !remaining,
-                            )?;
-                            std::fs::write(file_path, json!(current).to_string())?;
-                        }
+    Ok((current, remaining))
+}
+
+/// Notifies subscribers of every branch their PRs have reached since
+/// they were last notified.
+async fn update_subscribers() -> anyhow::Result<()> {
+    let store = store::store();
+    for project in PROJECTS.iter() {
+        let numbers = store.prs(&project.name)?;
+        let mut pr_infos = github()
+            .pr_infos(
+                &project.owner,
+                &project.repo,
+                &numbers,
+                CONFIG.github_batch_size,
+            )
+            .await;
+
+        for number in numbers {
+            let Some(pr_info) = pr_infos.remove(&number) else {
+                continue;
+            };
+            let pr = match pr_info {
+                Ok(pr_info) => track(project, number, pr_info).await,
+                Err(e) => {
+                    eprintln!("pr-tracker: {}#{}: {}", project.name, number, e);
+                    continue;
+                }
+            };
+            if let Some(ref tree) = pr.tree {
+                let (current, remaining) = record_progress(project, &pr, tree)?;
+                println!(
+                    "PR {}#{} is merged in: {:#?}",
+                    project.name, number, current
+                );
+                for subscription in store.subscriptions(&project.name, number)? {
+                    let mut to_do = &current - &subscription.notified;
+                    let mut last = !remaining;
+                    if !subscription.targets.is_empty() {
+                        to_do.retain(|branch| subscription.targets.contains(branch));
+                        last |= subscription.targets.is_subset(&current);
+                    }
+                    if !to_do.is_empty() {
+                        println!(
+                            "{} will be notified for: {:#?}",
+                            subscription.recipient, to_do
+                        );
+                        let notification = Notification {
+                            project: project.name.clone(),
+                            pr: pr.number,
+                            pr_title: pr.title.clone(),
+                            branches: tree.branches(&to_do),
+                            last,
+                            filter: subscription.filter,
+                        };
+                        store.queue(&subscription.recipient, &notification)?;
                     }
                 }
+                // Notifications are delivered from the outbox, so the
+                // last ones don't need the PR any more.
                 if !remaining {
-                    println!("Removing {}", dir_name);
-                    remove_dir_all(dir_path)?;
+                    println!("Removing {}#{}", project.name, number);
+                    store.remove_pr(&project.name, number)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Template)]
+#[template(path = "unsubscribe.html")]
+struct UnsubscribeTemplate {
+    error: Option<String>,
+    target: Option<UnsubscribeTarget>,
+    done: bool,
+}
+
+/// Who is being unsubscribed from what.
+struct UnsubscribeTarget {
+    recipient: Recipient,
+    what: String,
+}
+
+/// Shows what an unsubscribe link will do, and does it when the form
+/// is submitted, or when a mail client POSTs to the link from a
+/// `List-Unsubscribe` header.
+async fn unsubscribe<S>(request: Request<S>) -> http_types::Result<Response> {
+    let TokenQuery { token } = request.query()?;
+    let post = request.method() == http_types::Method::Post;
+
+    let claims = token::verify(&token)
+        .map_err(|e| eprintln!("pr-tracker: unsubscribe: {}", e))
+        .ok();
+
+    let mut page = UnsubscribeTemplate {
+        error: None,
+        target: None,
+        done: false,
+    };
+
+    // Unsubscribing from a single PR only applies to the project it's
+    // in, but unsubscribing from everything applies to every project.
+    match claims {
+        Some(token::Claims::Unsubscribe {
+            recipient,
+            repo: Some(repo),
+            pr: Some(pr),
+            filter: None,
+        }) => {
+            if let Some(project) = project::find(Some(&repo)) {
+                if post {
+                    store::store().unsubscribe(&project.name, pr, &recipient)?;
                 }
+                page.target = Some(UnsubscribeTarget {
+                    recipient,
+                    what: format!("{} PR #{}", project.title(), pr),
+                });
+            }
+        }
+        Some(token::Claims::Unsubscribe {
+            recipient,
+            repo: None,
+            pr: None,
+            filter: None,
+        }) => {
+            if post {
+                store::store().unsubscribe_all(&recipient)?;
             }
+            page.target = Some(UnsubscribeTarget {
+                recipient,
+                what: "any PRs".to_string(),
+            });
         }
+        Some(token::Claims::Unsubscribe {
+            recipient,
+            repo: Some(repo),
+            pr: None,
+            filter: Some(filter),
+        }) => {
+            if let Some(project) = project::find(Some(&repo)) {
+                if post {
+                    store::store().unsubscribe_standing(&project.name, &filter, &recipient)?;
+                }
+                page.target = Some(UnsubscribeTarget {
+                    recipient,
+                    what: format!("new {} PRs matching {}", project.title(), filter),
+                });
+            }
+        }
+        _ => {}
     }
-    Ok(Response::builder(200)
+
+    let status = if page.target.is_some() {
+        page.done = post;
+        200
+    } else {
+        page.error = Some("This unsubscribe link is invalid.".to_string());
+        400
+    };
+
+    Ok(Response::builder(status)
         .content_type(mime::HTML)
-        .body("Sucess")
+        .body(page.render()?)
         .build())
 }
 
-async fn unsubscribe<S>(request: Request<S>) -> http_types::Result<Response> {
-    let pr_number = request.query::<Query>()?.pr;
-    let email = request.query::<Query>()?.email;
-
-    if let Some(email) = email {
-        for f in read_dir(CONFIG.data_folder.clone())? {
-            let dir_path = f?.path();
-            let dir_name = dir_path.file_name().and_then(|x| x.to_str()).unwrap();
-            if dir_path.is_dir()
-                && (pr_number.is_none() || pr_number.as_ref().is_some_and(|x| x == dir_name))
-            {
-                match remove_file(dir_path.join(&email)) {
-                    Ok(_) => {}
-                    Err(_) => {}
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+async fn confirm<S>(request: Request<S>) -> http_types::Result<Response> {
+    let TokenQuery { token } = request.query()?;
+
+    let claims = token::verify(&token)
+        .map_err(|e| eprintln!("pr-tracker: confirm: {}", e))
+        .ok();
+
+    let mut page = PageTemplate::new(None);
+    match claims {
+        Some(token::Claims::Confirm {
+            repo,
+            pr,
+            recipient,
+            delivery,
+        }) => {
+            if let Some(project) = project::find(Some(&repo)) {
+                if store::store().confirm(&project.name, pr, &recipient)? {
+                    if let Some(delivery) = delivery {
+                        store::store().set_delivery(&recipient, delivery)?;
+                    }
+                    page = PageTemplate::new(Some(repo));
+                    page.email = Some(recipient.to_string());
+                    page.subscribed = true;
+                    match track_pr(project, pr).await {
+                        Ok(pr) => page.show_pr(project, pr),
+                        Err(e) => page.error = Some(e.to_string()),
+                    }
                 }
             }
         }
+        Some(token::Claims::ConfirmStanding {
+            repo,
+            filter,
+            recipient,
+            delivery,
+        }) => {
+            if let Some(project) = project::find(Some(&repo)) {
+                if store::store().confirm_standing(&project.name, &filter, &recipient)? {
+                    if let Some(delivery) = delivery {
+                        store::store().set_delivery(&recipient, delivery)?;
+                    }
+                    page = PageTemplate::new(Some(repo));
+                    page.email = Some(recipient.to_string());
+                    page.filter = Some(filter.to_string());
+                    page.subscribed = true;
+                }
+            }
+        }
+        _ => {}
     }
 
-    Ok(Response::builder(200)
+    let status = if page.subscribed {
+        200
+    } else {
+        page.error = Some(
+            "This confirmation link is invalid or has expired. Please subscribe again.".to_string(),
+        );
+        400
+    };
+
+    Ok(Response::builder(status)
         .content_type(mime::HTML)
-        .body("")
+        .body(page.render()?)
         .build())
 }
 
+/// How long to wait before sending another confirmation for the same
+/// subscription.  At most a few are sent in each confirmation period,
+/// so that nobody can be flooded with them.
+fn resend_after() -> Duration {
+    Duration::from_secs(CONFIG.confirmation_period / 4)
+}
+
+/// Sends `recipient` a link to confirm their subscription to `target`,
+/// carrying `claims`, and shows on `page` whether that worked.
+/// Returns the status to respond with.
+async fn send_confirmation(
+    page: &mut PageTemplate,
+    project: &Project,
+    recipient: &Recipient,
+    target: &Target<'_>,
+    claims: token::Claims,
+) -> u16 {
+    let valid_for = Duration::from_secs(CONFIG.confirmation_period);
+    let link = format!(
+        "{}/confirm?token={}",
+        CONFIG.url,
+        token::sign(claims, Some(valid_for))
+    );
+    match notifier::confirm(project, recipient, target, &link).await {
+        Ok(()) => {
+            page.confirming = true;
+            200
+        }
+        Err(e) => {
+            eprintln!("pr-tracker: sending confirmation to {}: {}", recipient, e);
+            page.error = Some(format!("We couldn't send a confirmation to {}.", recipient));
+            502
+        }
+    }
+}
+
 async fn handle_request<S>(request: Request<S>) -> http_types::Result<Response> {
     let mut status = 200;
-    let mut page = PageTemplate {
-        ..Default::default()
-    };
 
-    let pr_number = request.query::<Query>()?.pr;
-    let email = request.query::<Query>()?.email;
+    let Query {
+        repo,
+        pr: pr_number,
+        email,
+        filter,
+        delivery,
+        targets,
+    } = Query::parse(&request);
+    let mut page = PageTemplate::new(repo);
     page.email = email.clone();
+    page.filter = filter.clone();
+    page.delivery = delivery.clone();
+    page.targets = targets.iter().cloned().collect();
 
-    match pr_number.clone() {
-        Some(pr_number) => track_pr(pr_number, &mut status, &mut page).await,
-        None => {}
+    // Check what we've been given here, so that nothing further on
+    // has to deal with things that aren't PR numbers or recipients.
+    let pr_number = match pr_number.as_deref().map(str::parse::<PrNumber>).transpose() {
+        Ok(pr_number) => pr_number,
+        Err(e) => {
+            status = 400;
+            page.error = Some(e.to_string());
+            None
+        }
     };
-    match email {
-        Some(email) => {
-            if let Some(ref tree) = page.tree {
-                let mut v = Vec::new();
-                let remaining = tree.collect_branches(&mut v);
-                if !remaining {
-                    page.error = Some("There are no branches remaining to be tracked".to_string())
-                } else if !WHITE_LIST.is_empty() && !WHITE_LIST.contains(&email) {
-                    page.error = Some("You are not part of the white list.".to_string())
+    let filter = match filter
+        .as_deref()
+        .filter(|filter| !filter.is_empty())
+        .map(str::parse::<Filter>)
+        .transpose()
+    {
+        Ok(filter) => filter,
+        Err(e) => {
+            status = 400;
+            page.error = Some(e.to_string());
+            None
+        }
+    };
+    let delivery = Delivery::choice(delivery.as_deref());
+    let recipient = email
+        .as_deref()
+        .filter(|email| !email.is_empty())
+        .map(str::parse::<Recipient>)
+        .transpose();
+
+    match page.project {
+        Some(project) => {
+            if let Some(pr_number) = pr_number {
+                match track_pr_and_backports(project, pr_number).await {
+                    Ok(pr) => page.show_pr(project, pr),
+                    Err(e) => {
+                        status = e.status();
+                        page.error = Some(e.to_string());
+                    }
+                }
+            }
+        }
+        None => {
+            status = 404;
+            page.error = Some(format!(
+                "No such project: {}.",
+                page.repo.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+    let recipient = match recipient {
+        Ok(Some(Recipient::Matrix(_))) if matrix::homeserver().is_none() => {
+            status = 400;
+            page.error = Some("Matrix notifications aren't enabled here.".to_string());
+            None
+        }
+        Ok(Some(Recipient::Email(_))) if mail::mailer().is_none() => {
+            status = 400;
+            page.error = Some("Email notifications aren't enabled here.".to_string());
+            None
+        }
+        Ok(recipient) => recipient,
+        Err(e) => {
+            status = 400;
+            page.error = Some(e.to_string());
+            None
+        }
+    };
+    // Nobody should be subscribed in a way they didn't ask for.
+    let (recipient, delivery) = match delivery {
+        Ok(delivery) => (recipient, delivery),
+        Err(e) => {
+            status = 400;
+            page.error = Some(e.to_string());
+            (None, None)
+        }
+    };
+    if let Some(recipient) = recipient {
+        let white_list = &reload::rules().white_list;
+        let allowed = white_list.is_empty() || white_list.contains(&recipient);
+        if let Some(ref tree) = page.tree {
+            let mut v = Vec::new();
+            let remaining = tree.collect_branches(&mut v);
+            if let Err(e) = tree.check_targets(&targets) {
+                status = 400;
+                page.error = Some(e.to_string());
+            } else if !remaining {
+                page.error = Some("There are no branches remaining to be tracked".to_string())
+            } else if !allowed {
+                page.error = Some("You are not part of the white list.".to_string())
+            } else {
+                let project = page.project.unwrap();
+                let number = pr_number.unwrap();
+                let subscribing = store::store().subscribe(
+                    &project.name,
+                    number,
+                    &recipient,
+                    &v,
+                    &targets,
+                    resend_after(),
+                )?;
+                if subscribing == Subscribing::Pending {
+                    let claims = token::Claims::Confirm {
+                        repo: project.name.clone(),
+                        pr: number,
+                        recipient: recipient.clone(),
+                        delivery,
+                    };
+                    let title = page.pr_title.clone().unwrap_or_default();
+                    let target = Target::Pr {
+                        number,
+                        title: &title,
+                    };
+                    status =
+                        send_confirmation(&mut page, project, &recipient, &target, claims).await;
                 } else {
-                    page.subscribed = true;
-                    let folder = format!("{}/{}", CONFIG.data_folder, pr_number.unwrap());
-                    std::fs::create_dir_all(folder.clone())?;
-                    std::fs::write(format!("{folder}/{email}"), json!(v).to_string())?;
+                    page.show_subscribing(subscribing);
+                }
+            }
+        } else if let (Some(project), Some(filter)) = (page.project, filter) {
+            if !allowed {
+                page.error = Some("You are not part of the white list.".to_string())
+            } else {
+                let subscribing = store::store().subscribe_standing(
+                    &project.name,
+                    &filter,
+                    &recipient,
+                    resend_after(),
+                )?;
+                if subscribing == Subscribing::Pending {
+                    let claims = token::Claims::ConfirmStanding {
+                        repo: project.name.clone(),
+                        filter: filter.clone(),
+                        recipient: recipient.clone(),
+                        delivery,
+                    };
+                    let target = Target::Filter(&filter);
+                    status =
+                        send_confirmation(&mut page, project, &recipient, &target, claims).await;
+                } else {
+                    page.show_subscribing(subscribing);
                 }
             }
         }
-        None => {}
     }
 
     Ok(Response::builder(status)
@@ -315,13 +973,23 @@ async fn main() {
     // Make sure arguments are parsed before starting server.
     let _ = *CONFIG;
     let _ = *GITHUB_TOKEN;
+    let _ = *token::SECRET;
+    let _ = mail::mailer();
+    let _ = matrix::homeserver();
+    let _ = *PROJECTS;
+    let _ = reload::rules();
+    let _ = store::store();
 
     let mut server = tide::new();
     let mut root = server.at(&CONFIG.mount);
 
     root.at("/").get(handle_request);
-    root.at("update").get(update_subscribers);
-    root.at("unsubscribe").get(unsubscribe);
+    root.at("confirm").get(confirm);
+    root.at("unsubscribe").get(unsubscribe).post(unsubscribe);
+    root.at("manage").get(manage::manage).post(manage::manage);
+    root.at("api/v1/pr/:number").get(api::pr);
+    root.at("feed/pr/:file").get(feed::pr);
+    root.at("feed/prs.atom").get(feed::prs);
 
     let fd_count = handle_error(listen_fds(true), 71, "sd_listen_fds");
 
@@ -332,6 +1000,37 @@ async fn main() {
 
     let mut listeners: Vec<Pin<Box<dyn Future<Output = _>>>> = Vec::new();
 
+    // Background work runs alongside the listeners, so that they keep
+    // accepting connections while branches are fetched, subscribers
+    // are notified, or the rules are re-read.
+    if CONFIG.fetch_interval != 0 {
+        let interval = Duration::from_secs(CONFIG.fetch_interval);
+        listeners.push(Box::pin(async move {
+            project::fetch_periodically(interval).await;
+            Ok(())
+        }));
+    }
+
+    listeners.push(Box::pin(async move {
+        outbox::deliver_periodically().await;
+        Ok(())
+    }));
+
+    if CONFIG.update_interval != 0 {
+        let interval = Duration::from_secs(CONFIG.update_interval);
+        let jitter = Duration::from_secs(CONFIG.update_jitter);
+        listeners.push(Box::pin(async move {
+            scheduler::update_periodically(interval, jitter).await;
+            Ok(())
+        }));
+    }
+
+    let signals = handle_error(Signals::new([SIGHUP]), 71, "signals");
+    listeners.push(Box::pin(async move {
+        reload::reload_on(signals).await;
+        Ok(())
+    }));
+
     for fd in (3..).take(fd_count as usize) {
         let s = server.clone();
         if handle_error(is_socket_inet(fd), 74, "sd_is_socket_inet") {
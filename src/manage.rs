@@ -0,0 +1,345 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! A page where subscribers can see and change all of their
+//! subscriptions, without an account.  They ask for a link to it,
+//! which is sent the same way as their notifications, so following it
+//! shows that the subscriptions are theirs.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use askama::Template;
+use http_types::mime;
+use tide::{Request, Response};
+
+use crate::project::{self, Project};
+use crate::store::{self, Subscribed};
+use crate::token::{self, Claims};
+use crate::tree::Tree;
+use crate::types::{Delivery, Filter, PrNumber, Recipient};
+use crate::{github, mail, matrix, notifier, track, track_pr, CONFIG};
+
+/// A subscription to a PR, and how far the PR has got.
+struct ManagedPr {
+    project: &'static Project,
+    number: PrNumber,
+    title: Option<String>,
+    /// The id of the form the PR's branches are ticked in.
+    form: String,
+    /// Closed PRs, and PRs GitHub couldn't tell us about, don't have
+    /// a tree.
+    tree: Option<Tree>,
+    targets: HashSet<String>,
+}
+
+impl ManagedPr {
+    fn link(&self) -> String {
+        self.project.pull_link(self.number)
+    }
+}
+
+/// Everything a recipient is subscribed to.
+struct Managing {
+    recipient: Recipient,
+    delivery: Delivery,
+    prs: Vec<ManagedPr>,
+    standing: Vec<(&'static Project, Filter)>,
+}
+
+#[derive(Template)]
+#[template(path = "manage.html")]
+struct ManageTemplate {
+    error: Option<String>,
+    /// What the last change did.
+    notice: Option<String>,
+    email: Option<String>,
+    /// Whether a link to the page has just been asked for.
+    sent: bool,
+    managing: Option<Managing>,
+}
+
+/// What the page was asked to do, from its query or a submitted form.
+/// As on the main page, ticked branches are each their own `target`
+/// parameter.
+#[derive(Debug, Default)]
+struct Form {
+    token: Option<String>,
+    email: Option<String>,
+    action: Option<String>,
+    repo: Option<String>,
+    pr: Option<String>,
+    filter: Option<String>,
+    delivery: Option<String>,
+    targets: Vec<String>,
+}
+
+impl Form {
+    fn add(&mut self, key: &str, value: String) {
+        match key {
+            "token" => self.token = Some(value),
+            "email" => self.email = Some(value),
+            "action" => self.action = Some(value),
+            "repo" => self.repo = Some(value),
+            "pr" => self.pr = Some(value),
+            "filter" => self.filter = Some(value),
+            "delivery" => self.delivery = Some(value),
+            "target" => self.targets.push(value),
+            _ => {}
+        }
+    }
+}
+
+/// Sends `recipient` a link to the page, if they're subscribed to
+/// anything.  Whether they are isn't shown, so that the page can't be
+/// used to find out who is subscribed.
+async fn send_link(recipient: &Recipient) -> store::Result<()> {
+    let store = store::store();
+    if store.subscribed(recipient)?.is_empty() && store.standing_of(recipient)?.is_empty() {
+        return Ok(());
+    }
+
+    let valid_for = Duration::from_secs(CONFIG.confirmation_period);
+    let claims = Claims::Manage {
+        recipient: recipient.clone(),
+    };
+    let link = format!(
+        "{}/manage?token={}",
+        CONFIG.url,
+        token::sign(claims, Some(valid_for))
+    );
+    if let Err(e) = notifier::manage(recipient, &link).await {
+        eprintln!("pr-tracker: sending manage link to {}: {}", recipient, e);
+    }
+    Ok(())
+}
+
+fn parse_pr(form: &Form) -> Result<(&'static Project, PrNumber), String> {
+    let Some(project) = project::find(form.repo.as_deref()) else {
+        let repo = form.repo.as_deref().unwrap_or_default();
+        return Err(format!("No such project: {}.", repo));
+    };
+    let number = form
+        .pr
+        .as_deref()
+        .unwrap_or_default()
+        .parse::<PrNumber>()
+        .map_err(|e| e.to_string())?;
+    Ok((project, number))
+}
+
+/// Makes the change a form on the page asked for, and returns what it
+/// did.
+async fn change(recipient: &Recipient, form: &Form) -> store::Result<Result<String, String>> {
+    let store = store::store();
+    match form.action.as_deref() {
+        Some("delivery") => {
+            let delivery = match form
+                .delivery
+                .as_deref()
+                .unwrap_or_default()
+                .parse::<Delivery>()
+            {
+                Ok(delivery) => delivery,
+                Err(e) => return Ok(Err(e.to_string())),
+            };
+            store.set_delivery(recipient, delivery)?;
+            Ok(Ok("Your delivery preference has been saved.".to_string()))
+        }
+        Some("targets") => {
+            let (project, number) = match parse_pr(form) {
+                Ok(pr) => pr,
+                Err(e) => return Ok(Err(e)),
+            };
+            let pr = match track_pr(project, number).await {
+                Ok(pr) => pr,
+                Err(e) => return Ok(Err(e.to_string())),
+            };
+            let Some(tree) = pr.tree else {
+                return Ok(Err(format!("PR #{} was closed.", number)));
+            };
+            if let Err(e) = tree.check_targets(&form.targets) {
+                return Ok(Err(e.to_string()));
+            }
+            if !store.set_targets(&project.name, number, recipient, &form.targets)? {
+                return Ok(Err(format!(
+                    "You aren't subscribed to {} PR #{}.",
+                    project.title(),
+                    number
+                )));
+            }
+            Ok(Ok(format!(
+                "The branches for {} PR #{} have been saved.",
+                project.title(),
+                number
+            )))
+        }
+        Some("remove") => {
+            let (project, number) = match parse_pr(form) {
+                Ok(pr) => pr,
+                Err(e) => return Ok(Err(e)),
+            };
+            store.unsubscribe(&project.name, number, recipient)?;
+            Ok(Ok(format!(
+                "You will no longer be notified about {} PR #{}.",
+                project.title(),
+                number
+            )))
+        }
+        Some("remove_standing") => {
+            let Some(project) = project::find(form.repo.as_deref()) else {
+                let repo = form.repo.as_deref().unwrap_or_default();
+                return Ok(Err(format!("No such project: {}.", repo)));
+            };
+            let filter: Filter = match form.filter.as_deref().unwrap_or_default().parse() {
+                Ok(filter) => filter,
+                Err(e) => return Ok(Err(e.to_string())),
+            };
+            store.unsubscribe_standing(&project.name, &filter, recipient)?;
+            Ok(Ok(format!(
+                "You will no longer be subscribed to new PRs matching {}.",
+                filter
+            )))
+        }
+        Some(action) => Ok(Err(format!("Unknown action: {}.", action))),
+        None => Ok(Err("No action given.".to_string())),
+    }
+}
+
+/// Looks up everything `recipient` is subscribed to, and how far each
+/// PR has got.
+async fn managing(recipient: Recipient) -> store::Result<Managing> {
+    let store = store::store();
+    let delivery = store.delivery(&recipient)?.unwrap_or_default();
+    let subscribed = store.subscribed(&recipient)?;
+
+    let mut prs = Vec::new();
+    for project in project::PROJECTS.iter() {
+        let subscribed: Vec<&Subscribed> = subscribed
+            .iter()
+            .filter(|subscribed| subscribed.project == project.name)
+            .collect();
+        let numbers: Vec<_> = subscribed.iter().map(|subscribed| subscribed.pr).collect();
+        let mut pr_infos = github()
+            .pr_infos(
+                &project.owner,
+                &project.repo,
+                &numbers,
+                CONFIG.github_batch_size,
+            )
+            .await;
+
+        for subscribed in subscribed {
+            let (title, tree) = match pr_infos.remove(&subscribed.pr) {
+                Some(Ok(pr_info)) => {
+                    let pr = track(project, subscribed.pr, pr_info).await;
+                    (Some(pr.title), pr.tree)
+                }
+                Some(Err(e)) => {
+                    eprintln!("pr-tracker: {}#{}: {}", project.name, subscribed.pr, e);
+                    (None, None)
+                }
+                None => (None, None),
+            };
+            prs.push(ManagedPr {
+                project,
+                number: subscribed.pr,
+                title,
+                form: format!("pr-{}", prs.len()),
+                tree,
+                targets: subscribed.targets.clone(),
+            });
+        }
+    }
+
+    let standing = store
+        .standing_of(&recipient)?
+        .into_iter()
+        .filter_map(|(name, filter)| Some((project::find(Some(&name))?, filter)))
+        .collect();
+
+    Ok(Managing {
+        recipient,
+        delivery,
+        prs,
+        standing,
+    })
+}
+
+/// Sends links to the page when asked for one, and shows and changes
+/// the subscriptions of whoever a link was sent to.
+pub async fn manage<S>(mut request: Request<S>) -> http_types::Result<Response> {
+    let mut form = Form::default();
+    for (key, value) in request.url().query_pairs() {
+        form.add(&key, value.into_owned());
+    }
+    let post = request.method() == http_types::Method::Post;
+    if post {
+        // Submitted forms are read like queries, so that repeated
+        // targets are kept.
+        let body = request.body_string().await?;
+        let mut url = request.url().clone();
+        url.set_query(Some(&body));
+        for (key, value) in url.query_pairs() {
+            form.add(&key, value.into_owned());
+        }
+    }
+
+    let mut status = 200;
+    let mut page = ManageTemplate {
+        error: None,
+        notice: None,
+        email: form.email.clone(),
+        sent: false,
+        managing: None,
+    };
+
+    if let Some(ref token) = form.token {
+        let claims = token::verify(token)
+            .map_err(|e| eprintln!("pr-tracker: manage: {}", e))
+            .ok();
+        match claims {
+            Some(Claims::Manage { recipient }) => {
+                if post {
+                    match change(&recipient, &form).await? {
+                        Ok(notice) => page.notice = Some(notice),
+                        Err(e) => {
+                            status = 400;
+                            page.error = Some(e);
+                        }
+                    }
+                }
+                page.managing = Some(managing(recipient).await?);
+            }
+            _ => {
+                status = 400;
+                page.error = Some(
+                    "This link is invalid or has expired. Please ask for a new one.".to_string(),
+                );
+            }
+        }
+    } else if let Some(email) = form.email.as_deref().filter(|email| !email.is_empty()) {
+        match email.parse::<Recipient>() {
+            Ok(Recipient::Matrix(_)) if matrix::homeserver().is_none() => {
+                status = 400;
+                page.error = Some("Matrix notifications aren't enabled here.".to_string());
+            }
+            Ok(Recipient::Email(_)) if mail::mailer().is_none() => {
+                status = 400;
+                page.error = Some("Email notifications aren't enabled here.".to_string());
+            }
+            Ok(recipient) => {
+                send_link(&recipient).await?;
+                page.sent = true;
+            }
+            Err(e) => {
+                status = 400;
+                page.error = Some(e.to_string());
+            }
+        }
+    }
+
+    Ok(Response::builder(status)
+        .content_type(mime::HTML)
+        .body(page.render()?)
+        .build())
+}
@@ -0,0 +1,662 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Sends notifications to Matrix rooms and users, through the
+//! client-server API of `--matrix-homeserver`, as the user whose access
+//! token is in `PR_TRACKER_MATRIX_TOKEN`.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use askama::Template;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use surf::http::headers::HeaderValue;
+use surf::{Client, RequestBuilder, StatusCode};
+use urlencoding::encode;
+
+use crate::notifier::{self, unsubscribe_link, unsubscribe_standing_link, Notifier, Target};
+use crate::project::Project;
+use crate::store::{self, MatrixStore, Notification};
+use crate::tree::Branch;
+use crate::types::{Filter, MatrixId, PrNumber, Recipient};
+use crate::CONFIG;
+
+/// How long to wait for the homeserver to respond before trying again
+/// later.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Matrix IDs can't be notified without `--matrix-homeserver`.
+    NotConfigured,
+    Template(askama::Error),
+    Store(store::Error),
+    Request(surf::Error),
+    /// The homeserver's response status, and the `errcode` it gave, if
+    /// any.
+    Response(StatusCode, Option<String>),
+    /// Rooms are only joined when we've been invited to them.
+    NotInvited(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            NotConfigured => write!(f, "no homeserver configured"),
+            Template(e) => write!(f, "rendering message: {}", e),
+            Store(e) => write!(f, "{}", e),
+            Request(e) => write!(f, "request: {}", e),
+            Response(status, Some(code)) => write!(f, "response status {}: {}", status, code),
+            Response(status, None) => write!(f, "response status {}", status),
+            NotInvited(room) => write!(f, "not invited to {}", room),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the user or room can't be reached, because it doesn't
+    /// exist or won't let us in, so that trying again won't help.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Self::Response(StatusCode::Forbidden | StatusCode::NotFound, _) | Self::NotInvited(_)
+        )
+    }
+}
+
+impl From<askama::Error> for Error {
+    fn from(e: askama::Error) -> Self {
+        Self::Template(e)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(e: store::Error) -> Self {
+        Self::Store(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct RoomResponse {
+    room_id: String,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoomsResponse {
+    joined_rooms: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SyncResponse {
+    #[serde(default)]
+    rooms: SyncRooms,
+}
+
+#[derive(Default, Deserialize)]
+struct SyncRooms {
+    #[serde(default)]
+    invite: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    errcode: String,
+}
+
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    surf::Config::new()
+        .set_timeout(Some(TIMEOUT))
+        .try_into()
+        .unwrap()
+});
+
+/// A homeserver, and who to act as on it.
+pub struct Homeserver {
+    url: String,
+    token: String,
+}
+
+impl Homeserver {
+    pub fn new(url: &str, token: String) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+        body: Option<&Value>,
+    ) -> Result<T, Error> {
+        let authorization = HeaderValue::from_bytes(format!("Bearer {}", self.token).into())
+            .map_err(Error::Request)?;
+        let mut request = request.header("Authorization", authorization);
+        if let Some(body) = body {
+            request = request.body_json(body).map_err(Error::Request)?;
+        }
+        let mut response = request.send().await.map_err(Error::Request)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let code = response.body_json::<ErrorResponse>().await.ok();
+            return Err(Error::Response(status, code.map(|e| e.errcode)));
+        }
+        response.body_json().await.map_err(Error::Request)
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/_matrix/client/v3/{}", self.url, path)
+    }
+
+    /// Returns the ID of a room, looking it up if it's an alias.
+    async fn room_id(&self, room: &MatrixId) -> Result<String, Error> {
+        if !room.as_str().starts_with('#') {
+            return Ok(room.as_str().to_string());
+        }
+        let url = self.endpoint(&format!("directory/room/{}", encode(room.as_str())));
+        let response: RoomResponse = self.call(CLIENT.get(url), None).await?;
+        Ok(response.room_id)
+    }
+
+    /// Whether we've been invited to a room, and haven't joined it yet.
+    async fn is_invited(&self, room_id: &str) -> Result<bool, Error> {
+        let filter = json!({
+            "room": {
+                "rooms": [room_id],
+                "timeline": { "limit": 1 },
+                "state": { "types": [] },
+                "ephemeral": { "types": [] },
+                "account_data": { "types": [] },
+            },
+            "presence": { "types": [] },
+            "account_data": { "types": [] },
+        });
+        let url = self.endpoint(&format!(
+            "sync?timeout=0&filter={}",
+            encode(&filter.to_string())
+        ));
+        let response: SyncResponse = self.call(CLIENT.get(url), None).await?;
+        Ok(response.rooms.invite.contains_key(room_id))
+    }
+
+    /// Returns the ID of a room, joining it first if we've been invited.
+    /// Anybody can ask for notifications to go to a room, so rooms that
+    /// would let us in uninvited aren't joined, or their members could
+    /// be sent confirmation links nobody there asked for.
+    async fn join(&self, room: &MatrixId) -> Result<String, Error> {
+        let room_id = self.room_id(room).await?;
+
+        let url = self.endpoint("joined_rooms");
+        let joined: JoinedRoomsResponse = self.call(CLIENT.get(url), None).await?;
+        if joined.joined_rooms.contains(&room_id) {
+            return Ok(room_id);
+        }
+
+        if !self.is_invited(&room_id).await? {
+            return Err(Error::NotInvited(room.as_str().to_string()));
+        }
+        let url = self.endpoint(&format!("join/{}", encode(&room_id)));
+        let response: RoomResponse = self.call(CLIENT.post(url), Some(&json!({}))).await?;
+        Ok(response.room_id)
+    }
+
+    /// Creates a room to message `user` in, and invites them to it.
+    async fn create_direct_room(&self, user: &MatrixId) -> Result<String, Error> {
+        let body = json!({
+            "invite": [user.as_str()],
+            "is_direct": true,
+            "preset": "trusted_private_chat",
+            "name": "PR tracker",
+        });
+        let url = self.endpoint("createRoom");
+        let response: RoomResponse = self.call(CLIENT.post(url), Some(&body)).await?;
+        Ok(response.room_id)
+    }
+
+    async fn send_to_room(&self, room: &str, text: &str, html: &str) -> Result<(), Error> {
+        let url = self.endpoint(&format!(
+            "rooms/{}/send/m.room.message/{}",
+            encode(room),
+            fastrand::u64(..)
+        ));
+        let body = json!({
+            "msgtype": "m.notice",
+            "body": text,
+            "format": "org.matrix.custom.html",
+            "formatted_body": html,
+        });
+        let _: Value = self.call(CLIENT.put(url), Some(&body)).await?;
+        Ok(())
+    }
+
+    /// Sends a message to a room, or to a user in a room of their own,
+    /// which is created the first time, or if they've left the last
+    /// one.
+    pub async fn send(
+        &self,
+        store: &dyn MatrixStore,
+        to: &MatrixId,
+        text: &str,
+        html: &str,
+    ) -> Result<(), Error> {
+        if !to.is_user() {
+            let room = self.join(to).await?;
+            return self.send_to_room(&room, text, html).await;
+        }
+
+        if let Some(room) = store.direct_room(to)? {
+            match self.send_to_room(&room, text, html).await {
+                Err(Error::Response(StatusCode::Forbidden, _)) => {}
+                result => return result,
+            }
+        }
+        let room = self.create_direct_room(to).await?;
+        store.set_direct_room(to, &room)?;
+        self.send_to_room(&room, text, html).await
+    }
+}
+
+static HOMESERVER: Lazy<Option<Homeserver>> = Lazy::new(|| {
+    let url = CONFIG.matrix_homeserver.as_deref()?;
+    let token = match std::env::var("PR_TRACKER_MATRIX_TOKEN") {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("pr-tracker: PR_TRACKER_MATRIX_TOKEN: {}", e);
+            std::process::exit(78);
+        }
+    };
+    Some(Homeserver::new(url, token))
+});
+
+/// Returns the homeserver to send from, if there is one.
+pub fn homeserver() -> Option<&'static Homeserver> {
+    HOMESERVER.as_ref()
+}
+
+/// What a notification message says, in either format.
+struct NotificationMessage<'a> {
+    project_title: &'a str,
+    pr_number: PrNumber,
+    pr_title: &'a str,
+    pr_link: String,
+    branches: &'a [Branch],
+    /// Missing from the last notification about a PR.
+    unsubscribe: Option<String>,
+    /// The standing subscription that found the PR, and a link that
+    /// ends it.
+    unsubscribe_standing: Option<(&'a Filter, String)>,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/notification.html")]
+struct NotificationHtml<'a> {
+    message: &'a NotificationMessage<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/notification.txt")]
+struct NotificationText<'a> {
+    message: &'a NotificationMessage<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/digest.html")]
+struct DigestHtml<'a> {
+    messages: &'a [NotificationMessage<'a>],
+}
+
+#[derive(Template)]
+#[template(path = "matrix/digest.txt")]
+struct DigestText<'a> {
+    messages: &'a [NotificationMessage<'a>],
+}
+
+impl<'a> NotificationMessage<'a> {
+    fn new(project: &'a Project, to: &MatrixId, notification: &'a Notification) -> Self {
+        let pr_number = notification.pr;
+        let recipient = Recipient::Matrix(to.clone());
+        Self {
+            project_title: project.title(),
+            pr_number,
+            pr_title: &notification.pr_title,
+            pr_link: project.pull_link(pr_number),
+            branches: &notification.branches,
+            unsubscribe: (!notification.last)
+                .then(|| unsubscribe_link(&recipient, Some((project, pr_number)))),
+            unsubscribe_standing: notification.filter.as_ref().map(|filter| {
+                (
+                    filter,
+                    unsubscribe_standing_link(&recipient, project, filter),
+                )
+            }),
+        }
+    }
+}
+
+struct ConfirmationMessage<'a> {
+    project_title: &'a str,
+    target: &'a Target<'a>,
+    target_link: String,
+    link: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/confirmation.html")]
+struct ConfirmationHtml<'a> {
+    message: &'a ConfirmationMessage<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/confirmation.txt")]
+struct ConfirmationText<'a> {
+    message: &'a ConfirmationMessage<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/manage.html")]
+struct ManageHtml<'a> {
+    link: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "matrix/manage.txt")]
+struct ManageText<'a> {
+    link: &'a str,
+}
+
+/// Sends notifications to Matrix.
+pub struct Matrix;
+
+impl Notifier for Matrix {
+    type Address = MatrixId;
+
+    async fn notify(
+        &self,
+        project: &Project,
+        to: &MatrixId,
+        notification: &Notification,
+    ) -> Result<(), notifier::Error> {
+        let homeserver = homeserver().ok_or(Error::NotConfigured)?;
+        let message = NotificationMessage::new(project, to, notification);
+
+        let text = NotificationText { message: &message }
+            .render()
+            .map_err(Error::from)?;
+        let html = NotificationHtml { message: &message }
+            .render()
+            .map_err(Error::from)?;
+        Ok(homeserver.send(store::store(), to, &text, &html).await?)
+    }
+
+    async fn digest(
+        &self,
+        to: &MatrixId,
+        notifications: &[(&Project, &Notification)],
+    ) -> Result<(), notifier::Error> {
+        let homeserver = homeserver().ok_or(Error::NotConfigured)?;
+        let messages: Vec<_> = notifications
+            .iter()
+            .map(|(project, notification)| NotificationMessage::new(project, to, notification))
+            .collect();
+
+        let text = DigestText {
+            messages: &messages,
+        }
+        .render()
+        .map_err(Error::from)?;
+        let html = DigestHtml {
+            messages: &messages,
+        }
+        .render()
+        .map_err(Error::from)?;
+        Ok(homeserver.send(store::store(), to, &text, &html).await?)
+    }
+
+    async fn confirm(
+        &self,
+        project: &Project,
+        to: &MatrixId,
+        target: &Target<'_>,
+        link: &str,
+    ) -> Result<(), notifier::Error> {
+        let homeserver = homeserver().ok_or(Error::NotConfigured)?;
+        let message = ConfirmationMessage {
+            project_title: project.title(),
+            target,
+            target_link: target.link(project),
+            link,
+        };
+
+        let text = ConfirmationText { message: &message }
+            .render()
+            .map_err(Error::from)?;
+        let html = ConfirmationHtml { message: &message }
+            .render()
+            .map_err(Error::from)?;
+        Ok(homeserver.send(store::store(), to, &text, &html).await?)
+    }
+
+    async fn manage(&self, to: &MatrixId, link: &str) -> Result<(), notifier::Error> {
+        let homeserver = homeserver().ok_or(Error::NotConfigured)?;
+        let text = ManageText { link }.render().map_err(Error::from)?;
+        let html = ManageHtml { link }.render().map_err(Error::from)?;
+        Ok(homeserver.send(store::store(), to, &text, &html).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use async_std::task;
+    use tide::listener::Listener;
+    use tide::{Request, Response};
+
+    use crate::store::Sqlite;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Starts a homeserver where we're in `!joined:example.org`, have
+    /// been invited to `#nix:example.org` or `!nix:example.org`, and
+    /// could join any other room, and logs the requests it gets.
+    async fn mock_homeserver() -> (Homeserver, Log) {
+        let log = Log::default();
+        let mut server = tide::with_state(log.clone());
+
+        server.with(tide::utils::Before(|request: Request<Log>| async move {
+            let authorization = request.header("Authorization").map(|h| h.as_str());
+            assert_eq!(authorization, Some("Bearer secret"));
+            let path = urlencoding::decode(request.url().path()).unwrap();
+            let entry = format!("{} {}", request.method(), path);
+            request.state().lock().unwrap().push(entry);
+            request
+        }));
+
+        server.at("/_matrix/client/v3/directory/room/:alias").get(
+            |request: Request<Log>| async move {
+                Ok(match &*urlencoding::decode(request.param("alias")?)? {
+                    "#nix:example.org" => {
+                        Response::builder(200).body(json!({ "room_id": "!nix:example.org" }))
+                    }
+                    _ => Response::builder(404).body(json!({ "errcode": "M_NOT_FOUND" })),
+                })
+            },
+        );
+        server
+            .at("/_matrix/client/v3/joined_rooms")
+            .get(|_| async move {
+                Ok(Response::builder(200).body(json!({ "joined_rooms": ["!joined:example.org"] })))
+            });
+        server
+            .at("/_matrix/client/v3/sync")
+            .get(|request: Request<Log>| async move {
+                let filter = request
+                    .url()
+                    .query_pairs()
+                    .find(|(key, _)| key == "filter")
+                    .map(|(_, filter)| serde_json::from_str::<Value>(&filter).unwrap())
+                    .unwrap();
+                let mut invite = json!({});
+                if filter["room"]["rooms"] == json!(["!nix:example.org"]) {
+                    invite["!nix:example.org"] = json!({ "invite_state": { "events": [] } });
+                }
+                Ok(Response::builder(200).body(json!({
+                    "next_batch": "s1",
+                    "rooms": { "invite": invite },
+                })))
+            });
+        server
+            .at("/_matrix/client/v3/join/:room")
+            .post(|mut request: Request<Log>| async move {
+                // Unread bodies can stall the connection.
+                let _: Value = request.body_json().await?;
+                let room = urlencoding::decode(request.param("room")?)?.into_owned();
+                Ok(Response::builder(200).body(json!({ "room_id": room })))
+            });
+        server
+            .at("/_matrix/client/v3/createRoom")
+            .post(|mut request: Request<Log>| async move {
+                let body: Value = request.body_json().await?;
+                assert_eq!(body["invite"], json!(["@alice:example.org"]));
+                assert_eq!(body["is_direct"], json!(true));
+                Ok(Response::builder(200).body(json!({ "room_id": "!dm:example.org" })))
+            });
+        server
+            .at("/_matrix/client/v3/rooms/:room/send/m.room.message/:txn")
+            .put(|mut request: Request<Log>| async move {
+                let body: Value = request.body_json().await?;
+                assert_eq!(body["msgtype"], json!("m.notice"));
+                assert_eq!(body["body"], json!("hello"));
+                Ok(match &*urlencoding::decode(request.param("room")?)? {
+                    "!nix:example.org" | "!joined:example.org" | "!dm:example.org" => {
+                        Response::builder(200).body(json!({ "event_id": "$1" }))
+                    }
+                    _ => Response::builder(403).body(json!({ "errcode": "M_FORBIDDEN" })),
+                })
+            });
+
+        let mut listener = server.bind("127.0.0.1:0").await.unwrap();
+        let url = listener.info()[0].connection().to_string();
+        task::spawn(async move { listener.accept().await });
+
+        (Homeserver::new(&url, "secret".to_string()), log)
+    }
+
+    /// Returns the requests made since the last call, without
+    /// transaction IDs.
+    fn take(log: &Log) -> Vec<String> {
+        std::mem::take(&mut *log.lock().unwrap())
+            .into_iter()
+            .map(|request| match request.split_once("/m.room.message/") {
+                Some((prefix, _)) => format!("{}/m.room.message", prefix),
+                None => request,
+            })
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn rooms() {
+        let (homeserver, log) = mock_homeserver().await;
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+
+        let room = "#nix:example.org".parse().unwrap();
+        homeserver
+            .send(&store, &room, "hello", "hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            take(&log),
+            [
+                "GET /_matrix/client/v3/directory/room/#nix:example.org",
+                "GET /_matrix/client/v3/joined_rooms",
+                "GET /_matrix/client/v3/sync",
+                "POST /_matrix/client/v3/join/!nix:example.org",
+                "PUT /_matrix/client/v3/rooms/!nix:example.org/send/m.room.message",
+            ]
+        );
+
+        let joined = "!joined:example.org".parse().unwrap();
+        homeserver
+            .send(&store, &joined, "hello", "hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            take(&log),
+            [
+                "GET /_matrix/client/v3/joined_rooms",
+                "PUT /_matrix/client/v3/rooms/!joined:example.org/send/m.room.message",
+            ]
+        );
+
+        // Rooms anyone can join aren't, unless we were invited.
+        let public = "!public:example.org".parse().unwrap();
+        let error = homeserver
+            .send(&store, &public, "hello", "hello")
+            .await
+            .unwrap_err();
+        assert!(error.is_permanent(), "{}", error);
+        assert_eq!(
+            take(&log),
+            [
+                "GET /_matrix/client/v3/joined_rooms",
+                "GET /_matrix/client/v3/sync",
+            ]
+        );
+
+        let missing = "#missing:example.org".parse().unwrap();
+        let error = homeserver
+            .send(&store, &missing, "hello", "hello")
+            .await
+            .unwrap_err();
+        assert!(error.is_permanent(), "{}", error);
+    }
+
+    #[async_std::test]
+    async fn users() {
+        let (homeserver, log) = mock_homeserver().await;
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        let alice = "@alice:example.org".parse().unwrap();
+
+        homeserver
+            .send(&store, &alice, "hello", "hello")
+            .await
+            .unwrap();
+        homeserver
+            .send(&store, &alice, "hello", "hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            take(&log),
+            [
+                "POST /_matrix/client/v3/createRoom",
+                "PUT /_matrix/client/v3/rooms/!dm:example.org/send/m.room.message",
+                "PUT /_matrix/client/v3/rooms/!dm:example.org/send/m.room.message",
+            ]
+        );
+        assert_eq!(
+            store.direct_room(&alice).unwrap().as_deref(),
+            Some("!dm:example.org")
+        );
+
+        // If they've left, they get a new room.
+        store.set_direct_room(&alice, "!left:example.org").unwrap();
+        homeserver
+            .send(&store, &alice, "hello", "hello")
+            .await
+            .unwrap();
+        assert_eq!(take(&log).len(), 3);
+        assert_eq!(
+            store.direct_room(&alice).unwrap().as_deref(),
+            Some("!dm:example.org")
+        );
+    }
+}
@@ -1,21 +1,29 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
 // SPDX-FileCopyrightText: 2021 Alyssa Ross <hi@alyssa.is>
 
-use std::collections::BTreeSet;
-use std::ffi::OsStr;
-use std::ffi::OsString;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Display, Formatter};
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-use async_std::io;
 use async_std::process::{Command, Stdio};
+use async_std::task::spawn_blocking;
+use async_std::{fs, io, sync};
+use clap::ValueEnum;
+use git2::{ErrorCode, Oid, Repository};
+use humantime::{format_rfc3339_seconds, parse_rfc3339};
+use once_cell::sync::Lazy;
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     ExitFailure(ExitStatus),
+    Git(git2::Error),
 }
 
 impl Display for Error {
@@ -27,14 +35,37 @@ impl Display for Error {
                 Some(code) => write!(f, "git exited {}", code),
                 None => write!(f, "git killed by signal {}", e.signal().unwrap()),
             },
+            Git(e) => write!(f, "libgit2: {}", e),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+impl Error {
+    /// Whether the error might be because we haven't fetched the
+    /// commit we were asked about yet.
+    fn is_missing_object(&self) -> bool {
+        match self {
+            Error::ExitFailure(status) => status.code().is_some(),
+            Error::Git(e) => e.code() == ErrorCode::NotFound,
+            Error::Io(_) => false,
+        }
+    }
+}
+
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// How to find out which branches contain a commit.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Backend {
+    /// Run `git branch --contains`.
+    Cli,
+    /// Read the repository in-process, with libgit2.
+    #[default]
+    Libgit2,
+}
+
 fn check_status(status: ExitStatus) -> Result<()> {
     if status.success() {
         Ok(())
@@ -43,14 +74,67 @@ fn check_status(status: ExitStatus) -> Result<()> {
     }
 }
 
+/// Somewhere to find out which remote branches contain a commit.
+pub trait Branches {
+    /// Adds those of `branches` that contain `commit` to `out`.
+    /// Branches that don't exist don't contain anything.
+    async fn branches_containing_commit(
+        &self,
+        commit: &str,
+        branches: &[&str],
+        out: &mut BTreeSet<OsString>,
+    ) -> Result<()>;
+}
+
+/// Held while each checkout is being fetched, so that fetches of the
+/// same checkout, periodic or because a commit was missing, don't run
+/// at the same time.
+static FETCHING: Lazy<Mutex<HashMap<PathBuf, Arc<sync::Mutex<()>>>>> = Lazy::new(Default::default);
+
+/// The file in a checkout's Git directory that records when it was
+/// last fetched successfully, so that it's remembered across
+/// restarts.
+const LAST_FETCH_FILE: &str = "pr-tracker-last-fetch";
+
+fn last_fetch_file(path: &Path) -> Result<PathBuf> {
+    let repo = Repository::open(path).map_err(Error::Git)?;
+    Ok(repo.path().join(LAST_FETCH_FILE))
+}
+
+pub fn last_fetch(path: &Path) -> Option<SystemTime> {
+    let time = std::fs::read_to_string(last_fetch_file(path).ok()?).ok()?;
+    parse_rfc3339(time.trim()).ok()
+}
+
 pub struct Nixpkgs<'a> {
     path: &'a Path,
     remote_name: &'a Path,
+    backend: Backend,
+    /// Patterns matching the branches to fetch.
+    branch_globs: BTreeSet<String>,
+    fetched: AtomicBool,
 }
 
 impl<'a> Nixpkgs<'a> {
-    pub fn new(path: &'a Path, remote_name: &'a Path) -> Self {
-        Self { path, remote_name }
+    pub fn new(
+        path: &'a Path,
+        remote_name: &'a Path,
+        backend: Backend,
+        branch_globs: BTreeSet<String>,
+    ) -> Self {
+        Self {
+            path,
+            remote_name,
+            backend,
+            branch_globs,
+            fetched: AtomicBool::new(false),
+        }
+    }
+
+    fn remote_prefix(&self) -> PathBuf {
+        let mut prefix = PathBuf::from("refs/remotes/");
+        prefix.push(self.remote_name);
+        prefix
     }
 
     fn git_command(&self, subcommand: impl AsRef<OsStr>) -> Command {
@@ -61,11 +145,17 @@ impl<'a> Nixpkgs<'a> {
         command
     }
 
-    async fn git_branch_contains(&self, commit: &str) -> Result<Vec<u8>> {
+    /// Returns which of `branches` exist on the remote.
+    async fn remote_branches(&self, branches: &[&String]) -> Result<Vec<String>> {
+        if branches.is_empty() {
+            return Ok(Vec::new());
+        }
+
         let output = self
-            .git_command("branch")
-            .args(["-r", "--format=%(refname)", "--contains"])
-            .arg(commit)
+            .git_command("ls-remote")
+            .arg("--heads")
+            .arg(self.remote_name)
+            .args(branches)
             .stderr(Stdio::inherit())
             .output()
             .await
@@ -73,55 +163,311 @@ impl<'a> Nixpkgs<'a> {
 
         check_status(output.status)?;
 
-        Ok(output.stdout)
+        // Patterns match the end of ref names, so "staging" also
+        // matches "refs/heads/foo/staging".
+        let found: BTreeSet<&[u8]> = output
+            .stdout
+            .split(|byte| *byte == b'\n')
+            .filter_map(|line| line.split(|byte| *byte == b'\t').nth(1))
+            .filter_map(|r| r.strip_prefix(b"refs/heads/"))
+            .collect();
+
+        Ok(branches
+            .iter()
+            .filter(|branch| found.contains(branch.as_bytes()))
+            .map(|branch| branch.to_string())
+            .collect())
     }
 
-    async fn git_fetch_nixpkgs(&self) -> Result<()> {
-        // TODO: add refspecs
+    /// Fetches `branches`, which can be globs, pruning the refs of
+    /// branches matching them that have been deleted from the remote.
+    async fn fetch_branches(&self, branches: &[&String], stderr: Stdio) -> Result<ExitStatus> {
+        let refspecs = branches.iter().map(|glob| {
+            let mut refspec = OsString::from(format!("+refs/heads/{}:", glob));
+            refspec.push(self.remote_ref(glob));
+            refspec
+        });
+
         self.git_command("fetch")
+            .args(["--prune", "--quiet"])
             .arg(self.remote_name)
+            .args(refspecs)
+            .stderr(stderr)
             .status()
             .await
             .map_err(Error::Io)
-            .and_then(check_status)
     }
 
-    pub async fn branches_containing_commit(
+    /// Fetches the branches that PRs can progress through from the
+    /// remote, and nothing else.
+    pub async fn fetch(&self) -> Result<()> {
+        if self.branch_globs.is_empty() {
+            return Ok(());
+        }
+
+        let lock = FETCHING
+            .lock()
+            .unwrap()
+            .entry(self.path.to_path_buf())
+            .or_default()
+            .clone();
+        let _fetching = lock.lock().await;
+
+        let branches: Vec<_> = self.branch_globs.iter().collect();
+        if !self
+            .fetch_branches(&branches, Stdio::null())
+            .await?
+            .success()
+        {
+            // Globs that match nothing are fine, but fetching a branch
+            // that doesn't exist is an error, so find out which don't,
+            // forget what they used to point to, and fetch the rest.
+            let (exact, globs): (Vec<_>, Vec<_>) =
+                branches.into_iter().partition(|glob| !glob.contains('*'));
+            let found = self.remote_branches(&exact).await?;
+            for branch in exact.into_iter().filter(|branch| !found.contains(branch)) {
+                self.git_command("update-ref")
+                    .arg("-d")
+                    .arg(self.remote_ref(branch))
+                    .status()
+                    .await
+                    .map_err(Error::Io)
+                    .and_then(check_status)?;
+            }
+
+            let branches: Vec<_> = found.iter().chain(globs).collect();
+            if !branches.is_empty() {
+                let status = self.fetch_branches(&branches, Stdio::inherit()).await?;
+                check_status(status)?;
+            }
+        }
+
+        let now = format_rfc3339_seconds(SystemTime::now()).to_string();
+        fs::write(last_fetch_file(self.path)?, now)
+            .await
+            .map_err(Error::Io)
+    }
+
+    fn remote_ref(&self, branch: &str) -> PathBuf {
+        self.remote_prefix().join(branch)
+    }
+
+    async fn cli_ref_exists(&self, reference: &Path) -> Result<bool> {
+        self.git_command("rev-parse")
+            .args(["--verify", "--quiet"])
+            .arg(reference)
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .map(|status| status.success())
+            .map_err(Error::Io)
+    }
+
+    async fn cli_branch_contains(&self, branch: &str, commit: &str) -> Result<bool> {
+        let reference = self.remote_ref(branch);
+        let status = self
+            .git_command("merge-base")
+            .arg("--is-ancestor")
+            .arg(commit)
+            .arg(&reference)
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .map_err(Error::Io)?;
+
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            // git merge-base fails the same way whether the commit or
+            // the branch is missing, but only a missing commit is an
+            // error.  The branch might just not have been created yet.
+            _ if !self.cli_ref_exists(&reference).await? => Ok(false),
+            _ => Err(Error::ExitFailure(status)),
+        }
+    }
+
+    /// Lists which of `branches` contain `commit` by reading the
+    /// repository directly, which avoids starting a git process for
+    /// every lookup.  The repository is opened once for all of them.
+    async fn libgit2_branches_containing(
+        &self,
+        commit: &str,
+        branches: &[&str],
+    ) -> Result<Vec<OsString>> {
+        let path = self.path.to_path_buf();
+        let references: Vec<_> = branches
+            .iter()
+            .map(|branch| (OsString::from(branch), self.remote_ref(branch)))
+            .collect();
+        let commit = commit.to_string();
+
+        spawn_blocking(move || {
+            let repo = Repository::open(path)?;
+            let commit = repo.find_commit(Oid::from_str(&commit)?)?.id();
+
+            let mut containing = Vec::new();
+            for (branch, reference) in references {
+                let reference = String::from_utf8_lossy(reference.as_os_str().as_bytes());
+                let target = match repo.find_reference(&reference) {
+                    Ok(reference) => reference.peel_to_commit()?.id(),
+                    Err(e) if e.code() == ErrorCode::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                if target == commit || repo.graph_descendant_of(target, commit)? {
+                    containing.push(branch);
+                }
+            }
+
+            Ok(containing)
+        })
+        .await
+        .map_err(Error::Git)
+    }
+
+    async fn cli_branches_containing(
         &self,
         commit: &str,
+        branches: &[&str],
+    ) -> Result<Vec<OsString>> {
+        let mut containing = Vec::new();
+        for branch in branches {
+            if self.cli_branch_contains(branch, commit).await? {
+                containing.push(branch.into());
+            }
+        }
+        Ok(containing)
+    }
+
+    async fn branches_containing(&self, commit: &str, branches: &[&str]) -> Result<Vec<OsString>> {
+        match self.backend {
+            Backend::Cli => self.cli_branches_containing(commit, branches).await,
+            Backend::Libgit2 => self.libgit2_branches_containing(commit, branches).await,
+        }
+    }
+}
+
+impl Branches for Nixpkgs<'_> {
+    /// If the commit isn't known locally, the remote is fetched, but
+    /// only once for each `Nixpkgs`, so that looking up many branches
+    /// for a commit that doesn't exist doesn't fetch each time.
+    async fn branches_containing_commit(
+        &self,
+        commit: &str,
+        branches: &[&str],
         out: &mut BTreeSet<OsString>,
     ) -> Result<()> {
-        let output = match self.git_branch_contains(commit).await {
-            Err(Error::ExitFailure(status)) if status.code().is_some() => {
-                eprintln!("pr-tracker: git branch --contains failed; updating branches");
+        let containing = match self.branches_containing(commit, branches).await {
+            Err(e) if e.is_missing_object() && !self.fetched.swap(true, Ordering::Relaxed) => {
+                eprintln!("pr-tracker: {}; updating branches", e);
 
-                if let Err(e) = self.git_fetch_nixpkgs().await {
-                    eprintln!("pr-tracker: fetching nixpkgs: {}", e);
+                if let Err(e) = self.fetch().await {
+                    eprintln!("pr-tracker: fetching {}: {}", self.path.display(), e);
                     // Carry on, because it might have fetched what we
                     // need before dying.
                 }
 
-                self.git_branch_contains(commit).await?
+                self.branches_containing(commit, branches).await?
             }
 
-            Ok(output) => output,
-            Err(e) => return Err(e),
+            result => result?,
         };
 
-        let mut prefix = PathBuf::from("refs/remotes/");
-        prefix.push(self.remote_name);
+        out.extend(containing);
+        Ok(())
+    }
+}
 
-        for branch_name in output
-            .split(|byte| *byte == b'\n')
-            .filter(|b| !b.is_empty())
-            .map(OsStr::from_bytes)
-            .map(Path::new)
-            .filter_map(|r| r.strip_prefix(&prefix).ok())
-            .map(Into::into)
-        {
-            out.insert(branch_name);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(path: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .arg("-C")
+            .arg(path)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    fn remote_branches(path: &Path) -> Vec<String> {
+        let repo = Repository::open(path).unwrap();
+        let mut branches: Vec<_> = repo
+            .references_glob("refs/remotes/origin/*")
+            .unwrap()
+            .names()
+            .map(|name| {
+                name.unwrap()
+                    .trim_start_matches("refs/remotes/origin/")
+                    .to_string()
+            })
+            .collect();
+        branches.sort();
+        branches
+    }
+
+    #[async_std::test]
+    async fn fetch() {
+        let folder = std::env::temp_dir().join(format!("pr-tracker-fetch-{}", std::process::id()));
+        let upstream = folder.join("upstream");
+        let checkout = folder.join("checkout");
+        std::fs::create_dir_all(&folder).unwrap();
+
+        git(
+            &folder,
+            &["init", "--quiet", "--initial-branch=master", "upstream"],
+        );
+        git(
+            &upstream,
+            &["commit", "--quiet", "--allow-empty", "-m", "a"],
+        );
+        for branch in ["release-23.05", "release-23.11", "feature"] {
+            git(&upstream, &["branch", branch]);
         }
+        git(&folder, &["init", "--quiet", "checkout"]);
+        git(&checkout, &["remote", "add", "origin", "../upstream"]);
 
-        Ok(())
+        // Only the branches PRs can reach are fetched, and ones that
+        // don't exist yet are skipped rather than failing the fetch.
+        let globs = ["master", "staging", "release-*"].map(String::from).into();
+        let nixpkgs = Nixpkgs::new(&checkout, Path::new("origin"), Backend::Libgit2, globs);
+        assert_eq!(last_fetch(&checkout), None);
+        nixpkgs.fetch().await.unwrap();
+        assert_eq!(
+            remote_branches(&checkout),
+            ["master", "release-23.05", "release-23.11"]
+        );
+        assert!(last_fetch(&checkout).is_some());
+
+        git(&upstream, &["branch", "staging"]);
+        nixpkgs.fetch().await.unwrap();
+        assert_eq!(
+            remote_branches(&checkout),
+            ["master", "release-23.05", "release-23.11", "staging"]
+        );
+
+        // Branches deleted from the remote are pruned, whether they were
+        // asked for by name or by glob, but other refs are left alone.
+        git(
+            &checkout,
+            &[
+                "update-ref",
+                "refs/remotes/origin/mine",
+                "refs/remotes/origin/master",
+            ],
+        );
+        git(
+            &upstream,
+            &["branch", "--delete", "release-23.05", "staging"],
+        );
+        nixpkgs.fetch().await.unwrap();
+        assert_eq!(
+            remote_branches(&checkout),
+            ["master", "mine", "release-23.11"]
+        );
+
+        std::fs::remove_dir_all(&folder).unwrap();
     }
 }
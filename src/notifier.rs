@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! The ways subscribers can be told about PRs.
+
+use std::fmt::{self, Display, Formatter};
+
+use urlencoding::encode;
+
+use crate::mail::{self, Mail};
+use crate::matrix::{self, Matrix};
+use crate::project::Project;
+use crate::store::Notification;
+use crate::token::{self, Claims};
+use crate::types::{Filter, PrNumber, Recipient};
+use crate::webhook::{self, Webhook};
+use crate::CONFIG;
+
+#[derive(Debug)]
+pub enum Error {
+    Mail(mail::Error),
+    Matrix(matrix::Error),
+    Webhook(webhook::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Mail(e) => write!(f, "mail: {}", e),
+            Matrix(e) => write!(f, "matrix: {}", e),
+            Webhook(e) => write!(f, "webhook: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the problem is with where we're sending to, so that
+    /// trying again won't help.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::Mail(e) => e.is_permanent(),
+            Self::Matrix(e) => e.is_permanent(),
+            Self::Webhook(e) => e.is_permanent(),
+        }
+    }
+}
+
+impl From<mail::Error> for Error {
+    fn from(e: mail::Error) -> Self {
+        Self::Mail(e)
+    }
+}
+
+impl From<matrix::Error> for Error {
+    fn from(e: matrix::Error) -> Self {
+        Self::Matrix(e)
+    }
+}
+
+impl From<webhook::Error> for Error {
+    fn from(e: webhook::Error) -> Self {
+        Self::Webhook(e)
+    }
+}
+
+/// What somebody has asked to be subscribed to.
+pub enum Target<'a> {
+    Pr {
+        number: PrNumber,
+        title: &'a str,
+    },
+    /// Every PR that matches a filter.
+    Filter(&'a Filter),
+}
+
+impl Target<'_> {
+    /// Where to see it on GitHub.
+    pub fn link(&self, project: &Project) -> String {
+        match self {
+            Self::Pr { number, .. } => project.pull_link(number),
+            Self::Filter(filter) => project.search_link(filter),
+        }
+    }
+}
+
+impl Display for Target<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Pr { number, title } => write!(f, "PR #{} (\"{}\")", number, title),
+            Self::Filter(filter) => write!(f, "PRs {}", filter.description()),
+        }
+    }
+}
+
+/// Something that can tell subscribers about PRs.
+pub trait Notifier {
+    /// Where this notifier sends to.
+    type Address;
+
+    /// Tells `to` that a PR in `project` has reached new branches.
+    async fn notify(
+        &self,
+        project: &Project,
+        to: &Self::Address,
+        notification: &Notification,
+    ) -> Result<(), Error>;
+
+    /// Tells `to` about everything in a digest at once, each
+    /// notification alongside the project it belongs to.
+    async fn digest(
+        &self,
+        to: &Self::Address,
+        notifications: &[(&Project, &Notification)],
+    ) -> Result<(), Error>;
+
+    /// Asks `to` to confirm their subscription to `target`, by
+    /// following `link`.
+    async fn confirm(
+        &self,
+        project: &Project,
+        to: &Self::Address,
+        target: &Target<'_>,
+        link: &str,
+    ) -> Result<(), Error>;
+
+    /// Sends `to` a `link` to a page where they can see and change
+    /// their subscriptions.
+    async fn manage(&self, to: &Self::Address, link: &str) -> Result<(), Error>;
+}
+
+/// Returns a link that unsubscribes `recipient` from `pr` in
+/// `project`, or from everything if no PR is given.
+pub fn unsubscribe_link(recipient: &Recipient, pr: Option<(&Project, PrNumber)>) -> String {
+    let claims = Claims::Unsubscribe {
+        recipient: recipient.clone(),
+        repo: pr.map(|(project, _)| project.name.clone()),
+        pr: pr.map(|(_, number)| number),
+        filter: None,
+    };
+    format!(
+        "{}/unsubscribe?token={}",
+        CONFIG.url,
+        encode(&token::sign(claims, None))
+    )
+}
+
+/// Returns a link that ends `recipient`'s standing subscription to
+/// the PRs in `project` that match `filter`.
+pub fn unsubscribe_standing_link(
+    recipient: &Recipient,
+    project: &Project,
+    filter: &Filter,
+) -> String {
+    let claims = Claims::Unsubscribe {
+        recipient: recipient.clone(),
+        repo: Some(project.name.clone()),
+        pr: None,
+        filter: Some(filter.clone()),
+    };
+    format!(
+        "{}/unsubscribe?token={}",
+        CONFIG.url,
+        encode(&token::sign(claims, None))
+    )
+}
+
+/// Tells `recipient` about `notification`, in whichever way suits it.
+pub async fn notify(
+    project: &Project,
+    recipient: &Recipient,
+    notification: &Notification,
+) -> Result<(), Error> {
+    match recipient {
+        Recipient::Email(address) => Mail.notify(project, address, notification).await,
+        Recipient::Matrix(id) => Matrix.notify(project, id, notification).await,
+        Recipient::Webhook(url) => Webhook.notify(project, url, notification).await,
+    }
+}
+
+/// Tells `recipient` about everything in a digest, in whichever way
+/// suits it.
+pub async fn digest(
+    recipient: &Recipient,
+    notifications: &[(&Project, &Notification)],
+) -> Result<(), Error> {
+    match recipient {
+        Recipient::Email(address) => Mail.digest(address, notifications).await,
+        Recipient::Matrix(id) => Matrix.digest(id, notifications).await,
+        Recipient::Webhook(url) => Webhook.digest(url, notifications).await,
+    }
+}
+
+/// Asks `recipient` to confirm their subscription to `target`, in
+/// whichever way suits it.
+pub async fn confirm(
+    project: &Project,
+    recipient: &Recipient,
+    target: &Target<'_>,
+    link: &str,
+) -> Result<(), Error> {
+    match recipient {
+        Recipient::Email(address) => Mail.confirm(project, address, target, link).await,
+        Recipient::Matrix(id) => Matrix.confirm(project, id, target, link).await,
+        Recipient::Webhook(url) => Webhook.confirm(project, url, target, link).await,
+    }
+}
+
+/// Sends `recipient` a link to manage their subscriptions, in whichever
+/// way suits it.
+pub async fn manage(recipient: &Recipient, link: &str) -> Result<(), Error> {
+    match recipient {
+        Recipient::Email(address) => Mail.manage(address, link).await,
+        Recipient::Matrix(id) => Matrix.manage(id, link).await,
+        Recipient::Webhook(url) => Webhook.manage(url, link).await,
+    }
+}
@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Delivers the notifications that updates put in the outbox, and the
+//! digests collected from the ones kept back for them, retrying the
+//! ones that fail with exponential backoff.
+
+use std::cmp::min;
+use std::time::Duration;
+
+use async_std::sync::Mutex;
+use async_std::task::sleep;
+use once_cell::sync::Lazy;
+
+use crate::notifier;
+use crate::project::{Project, PROJECTS};
+use crate::store::{self, Message, Queued};
+use crate::types::Recipient;
+use crate::CONFIG;
+
+/// How long to wait before retrying a notification the first time.
+const MIN_RETRY: Duration = Duration::from_secs(60);
+
+/// The longest to wait between retries.
+const MAX_RETRY: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long to keep trying to deliver a message before giving up on
+/// it, even if it's never been refused outright.
+const MAX_AGE: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// How many notifications to fetch from the store at once.
+const BATCH_SIZE: usize = 50;
+
+/// How often to look for notifications that are due to be retried.
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Held while the outbox is being delivered, so that nothing is sent
+/// twice.
+static DELIVERY_LOCK: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+/// How long to wait before trying to deliver a notification again,
+/// after it has failed `attempts` times.
+fn backoff(attempts: u32) -> Duration {
+    MIN_RETRY
+        .checked_mul(1 << min(attempts, 16))
+        .map_or(MAX_RETRY, |retry| min(retry, MAX_RETRY))
+}
+
+fn find_project(name: &str) -> Option<&'static Project> {
+    PROJECTS.iter().find(|project| project.name == name)
+}
+
+/// Sends a message from the outbox.  Anything about a project that's no
+/// longer configured is left out.
+async fn send(queued: &Queued) -> Result<(), notifier::Error> {
+    match &queued.message {
+        Message::Notification(notification) => {
+            let Some(project) = find_project(&notification.project) else {
+                eprintln!(
+                    "pr-tracker: dropping notification for {}#{}, which is no longer configured",
+                    notification.project, notification.pr
+                );
+                return Ok(());
+            };
+            notifier::notify(project, &queued.recipient, notification).await
+        }
+        Message::Digest { notifications } => {
+            let notifications: Vec<_> = notifications
+                .iter()
+                .filter_map(|notification| {
+                    find_project(&notification.project).map(|project| (project, notification))
+                })
+                .collect();
+            if notifications.is_empty() {
+                return Ok(());
+            }
+            notifier::digest(&queued.recipient, &notifications).await
+        }
+    }
+}
+
+/// Disables `recipient` if delivering to it has failed too many times
+/// in a row.
+fn check_failures(recipient: &Recipient, failures: u32) -> store::Result<()> {
+    if failures >= CONFIG.max_delivery_failures {
+        eprintln!(
+            "pr-tracker: disabling {} after {} failed deliveries",
+            recipient, failures
+        );
+        store::store().disable(recipient)?;
+    }
+    Ok(())
+}
+
+async fn deliver_one(queued: Queued) -> store::Result<()> {
+    let store = store::store();
+    let Err(e) = send(&queued).await else {
+        return store.delivered(queued.id);
+    };
+
+    let about = match &queued.message {
+        Message::Notification(notification) => {
+            format!("{}#{}", notification.project, notification.pr)
+        }
+        Message::Digest { .. } => "digest".to_string(),
+    };
+    eprintln!(
+        "pr-tracker: {}: notifying {}: {}",
+        about, queued.recipient, e
+    );
+    let failures = store.failed(
+        queued.id,
+        &e.to_string(),
+        backoff(queued.attempts),
+        e.is_permanent(),
+    )?;
+    check_failures(&queued.recipient, failures)
+}
+
+/// Collects the digests that are due, then delivers every message in
+/// the outbox that's due, unless that's already being done.
+pub async fn deliver() {
+    let Some(_guard) = DELIVERY_LOCK.try_lock() else {
+        return;
+    };
+
+    match store::store().collect_digests() {
+        Ok(0) => {}
+        Ok(n) => println!("Collected {} digests", n),
+        Err(e) => eprintln!("pr-tracker: collecting digests: {}", e),
+    }
+
+    let expired = store::store().expire(MAX_AGE).and_then(|expired| {
+        for (recipient, failures) in expired {
+            eprintln!(
+                "pr-tracker: giving up on notifying {} after {}",
+                recipient,
+                humantime::format_duration(MAX_AGE)
+            );
+            check_failures(&recipient, failures)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = expired {
+        eprintln!("pr-tracker: expiring outbox: {}", e);
+    }
+
+    // Failed notifications aren't due again straight away, so this
+    // runs out.
+    loop {
+        let due = match store::store().due(BATCH_SIZE) {
+            Ok(due) if due.is_empty() => return,
+            Ok(due) => due,
+            Err(e) => {
+                eprintln!("pr-tracker: reading outbox: {}", e);
+                return;
+            }
+        };
+
+        for queued in due {
+            if let Err(e) = deliver_one(queued).await {
+                eprintln!("pr-tracker: updating outbox: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Delivers the outbox whenever messages might have become due.
+pub async fn deliver_periodically() {
+    loop {
+        deliver().await;
+        sleep(DELIVERY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), MIN_RETRY);
+        assert_eq!(backoff(1), MIN_RETRY * 2);
+        assert_eq!(backoff(3), MIN_RETRY * 8);
+        assert_eq!(backoff(9), MAX_RETRY);
+        assert_eq!(backoff(u32::MAX), MAX_RETRY);
+    }
+}
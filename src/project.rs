@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use async_std::task::sleep;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use urlencoding::encode;
+
+use crate::nixpkgs::{self, Nixpkgs};
+use crate::types::Filter;
+use crate::{reload, CONFIG};
+
+/// The name of the project configured by the top level command line
+/// arguments, which is used when a request doesn't name a project.
+pub const DEFAULT_PROJECT: &str = "nixpkgs";
+
+/// Where a project's pull requests are, unless it says otherwise.
+const DEFAULT_PULL_LINK: &str = "https://github.com/{owner}/{repo}/pull/{number}";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    InvalidName(String),
+    DuplicateName(String),
+    /// A `pull_link` that doesn't say where the PR number goes.
+    InvalidPullLink(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(e) => write!(f, "{}", e),
+            Parse(e) => write!(f, "{}", e),
+            InvalidName(name) => write!(f, "invalid project name: {:?}", name),
+            DuplicateName(name) => write!(f, "duplicate project name: {:?}", name),
+            InvalidPullLink(name) => write!(f, "{}: pull_link has no {{number}}", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A GitHub repository whose pull requests can be tracked.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    /// The name used to select the project in URLs.
+    pub name: String,
+    /// The name shown to users.  Defaults to `name`.
+    title: Option<String>,
+    pub owner: String,
+    pub repo: String,
+    /// The path to the local checkout of the repository.
+    pub path: PathBuf,
+    /// The git remote corresponding to the upstream repository.
+    pub remote: PathBuf,
+    /// Where to find the branch rules for this project.  Defaults to the
+    /// built-in rules for nixpkgs.
+    pub branch_config: Option<PathBuf>,
+    /// Where each pull request is, with `{owner}`, `{repo}` and
+    /// `{number}` filled in.  Defaults to its page on GitHub.
+    pull_link: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectsConfig {
+    #[serde(default)]
+    project: Vec<Project>,
+}
+
+impl Project {
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn pull_link(&self, number: impl Display) -> String {
+        self.pull_link
+            .as_deref()
+            .unwrap_or(DEFAULT_PULL_LINK)
+            .replace("{owner}", &self.owner)
+            .replace("{repo}", &self.repo)
+            .replace("{number}", &number.to_string())
+    }
+
+    /// Where to see the PRs matching `filter` on GitHub.
+    pub fn search_link(&self, filter: &Filter) -> String {
+        let query = format!("is:pr {}", filter.qualifier());
+        format!(
+            "https://github.com/{}/{}/pulls?q={}",
+            self.owner,
+            self.repo,
+            encode(&query)
+        )
+    }
+
+    pub fn checkout(&self) -> Nixpkgs<'_> {
+        let globs = reload::rules().branches(&self.name).next_branch_globs();
+        Nixpkgs::new(&self.path, &self.remote, CONFIG.git_backend, globs)
+    }
+
+    /// When the branches in the project's checkout were last fetched
+    /// successfully, if they ever have been.
+    pub fn last_fetch(&self) -> Option<SystemTime> {
+        nixpkgs::last_fetch(&self.path)
+    }
+
+    /// The folder that subscriptions to this project's pull requests
+    /// are saved in.
+    pub fn data_folder(&self) -> PathBuf {
+        let root = Path::new(&CONFIG.data_folder);
+        if self.name == DEFAULT_PROJECT {
+            root.to_path_buf()
+        } else {
+            root.join(&self.name)
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Project>, Error> {
+    let source = read_to_string(path).map_err(Error::Io)?;
+    let config: ProjectsConfig = toml::from_str(&source).map_err(Error::Parse)?;
+
+    let mut names = vec![DEFAULT_PROJECT];
+    for project in &config.project {
+        // Project data is stored alongside the numbered folders of the
+        // default project, so names must never look like PR numbers.
+        let valid = !project.name.is_empty()
+            && !project.name.bytes().all(|b| b.is_ascii_digit())
+            && !project.name.starts_with('.')
+            && project
+                .name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+        if !valid {
+            return Err(Error::InvalidName(project.name.clone()));
+        }
+        if names.contains(&project.name.as_str()) {
+            return Err(Error::DuplicateName(project.name.clone()));
+        }
+        if let Some(link) = &project.pull_link {
+            if !link.contains("{number}") {
+                return Err(Error::InvalidPullLink(project.name.clone()));
+            }
+        }
+        names.push(&project.name);
+    }
+
+    Ok(config.project)
+}
+
+pub static PROJECTS: Lazy<Vec<Project>> = Lazy::new(|| {
+    let default = Project {
+        name: DEFAULT_PROJECT.to_string(),
+        title: Some("Nixpkgs".to_string()),
+        owner: "NixOS".to_string(),
+        repo: "nixpkgs".to_string(),
+        path: CONFIG.path.clone(),
+        remote: CONFIG.remote.clone(),
+        branch_config: CONFIG.branch_config.clone(),
+        pull_link: None,
+    };
+
+    let mut projects = vec![default];
+    if let Some(path) = &CONFIG.projects {
+        match load(path) {
+            Ok(extra) => projects.extend(extra),
+            Err(e) => {
+                eprintln!("pr-tracker: {}: {}", path.display(), e);
+                std::process::exit(78);
+            }
+        }
+    }
+    projects
+});
+
+/// Fetches every project's checkout every `interval`, so that what we
+/// show about PRs is never too out of date.
+pub async fn fetch_periodically(interval: Duration) {
+    loop {
+        for project in PROJECTS.iter() {
+            if let Err(e) = project.checkout().fetch().await {
+                eprintln!("pr-tracker: fetching {}: {}", project.name, e);
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Looks up a project by name, falling back to the default project if
+/// no name is given.
+pub fn find(name: Option<&str>) -> Option<&'static Project> {
+    let name = name.unwrap_or(DEFAULT_PROJECT);
+    PROJECTS.iter().find(|project| project.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_str(source: &str) -> Result<Vec<Project>, Error> {
+        let path = std::env::temp_dir().join(format!("pr-tracker-projects-{}", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let projects = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        projects
+    }
+
+    #[test]
+    fn pull_links() {
+        let projects = load_str(
+            r#"
+[[project]]
+name = "home-manager"
+owner = "nix-community"
+repo = "home-manager"
+path = "/var/lib/pr-tracker/home-manager"
+remote = "origin"
+
+[[project]]
+name = "forge"
+owner = "owner"
+repo = "repo"
+path = "/var/lib/pr-tracker/forge"
+remote = "origin"
+pull_link = "https://git.example.com/{owner}/{repo}/pulls/{number}"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            projects[0].pull_link(123),
+            "https://github.com/nix-community/home-manager/pull/123"
+        );
+        assert_eq!(
+            projects[1].pull_link(123),
+            "https://git.example.com/owner/repo/pulls/123"
+        );
+
+        let e = load_str(
+            r#"
+[[project]]
+name = "forge"
+owner = "owner"
+repo = "repo"
+path = "/var/lib/pr-tracker/forge"
+remote = "origin"
+pull_link = "https://git.example.com/{owner}/{repo}/pulls"
+"#,
+        )
+        .unwrap_err();
+        assert!(matches!(e, Error::InvalidPullLink(name) if name == "forge"));
+    }
+}
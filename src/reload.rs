@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use signal_hook_async_std::Signals;
+
+use crate::branches::{self, BranchRules};
+use crate::project::PROJECTS;
+use crate::types::Recipient;
+use crate::CONFIG;
+
+#[derive(Debug)]
+pub enum Error {
+    BranchConfig(PathBuf, branches::Error),
+    WhiteList(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            BranchConfig(path, e) => write!(f, "{}: {}", path.display(), e),
+            WhiteList(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The parts of the configuration that are read from files, and so can
+/// be reloaded without restarting the server.
+pub struct Rules {
+    branches: BTreeMap<String, BranchRules>,
+    pub white_list: HashSet<Recipient>,
+}
+
+/// Reads a white list, with a recipient on each line, so that they're
+/// compared the same way subscribers are.
+fn load_white_list(path: &Path) -> io::Result<HashSet<Recipient>> {
+    let mut white_list = HashSet::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let recipient = line.trim().parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, e))
+        })?;
+        white_list.insert(recipient);
+    }
+    Ok(white_list)
+}
+
+impl Rules {
+    fn load() -> Result<Self, Error> {
+        let projects = PROJECTS
+            .iter()
+            .map(|project| (project.name.as_str(), project.branch_config.as_deref()));
+        Self::read(projects, CONFIG.email_white_list.as_deref())
+    }
+
+    /// Reads the rules for each project, given by its name and where
+    /// its branch rules are, if they aren't the built-in ones.
+    fn read<'a>(
+        projects: impl IntoIterator<Item = (&'a str, Option<&'a Path>)>,
+        white_list: Option<&Path>,
+    ) -> Result<Self, Error> {
+        let mut branches = BTreeMap::new();
+        for (name, branch_config) in projects {
+            let rules = match branch_config {
+                Some(path) => BranchRules::load(path)
+                    .map_err(|e| Error::BranchConfig(path.to_path_buf(), e))?,
+                None => BranchRules::default(),
+            };
+            branches.insert(name.to_string(), rules);
+        }
+
+        let white_list = match white_list {
+            Some(path) => {
+                load_white_list(path).map_err(|e| Error::WhiteList(path.to_path_buf(), e))?
+            }
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            branches,
+            white_list,
+        })
+    }
+
+    /// Returns the branch rules for the named project.
+    pub fn branches(&self, project: &str) -> &BranchRules {
+        &self.branches[project]
+    }
+
+    /// Describes how `self` differs from the rules it's replacing, a
+    /// line for each change.
+    fn changes(&self, old: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        for (project, rules) in &self.branches {
+            let old_branches = old.branches[project].describe();
+            let new_branches = rules.describe();
+            for rule in old_branches.difference(&new_branches) {
+                changes.push(format!("{}: removed branch rule: {}", project, rule));
+            }
+            for rule in new_branches.difference(&old_branches) {
+                changes.push(format!("{}: added branch rule: {}", project, rule));
+            }
+        }
+
+        let removed = old.white_list.difference(&self.white_list).count();
+        let added = self.white_list.difference(&old.white_list).count();
+        if removed != 0 || added != 0 {
+            changes.push(format!("white list: {} added, {} removed", added, removed));
+        }
+        changes
+    }
+}
+
+static RULES: Lazy<RwLock<Arc<Rules>>> = Lazy::new(|| match Rules::load() {
+    Ok(rules) => RwLock::new(Arc::new(rules)),
+    Err(e) => {
+        eprintln!("pr-tracker: {}", e);
+        std::process::exit(78);
+    }
+});
+
+/// Returns the rules currently in effect.  Callers should hold on to
+/// the result for the duration of a request, so that a reload halfway
+/// through doesn't mix old and new rules.
+pub fn rules() -> Arc<Rules> {
+    RULES.read().unwrap().clone()
+}
+
+/// Puts `new` in place of `current`, unless it couldn't be read, and
+/// returns what changed.
+fn replace(current: &RwLock<Arc<Rules>>, new: Result<Rules, Error>) -> Result<Vec<String>, Error> {
+    let new = new?;
+    let mut current = current.write().unwrap();
+    let changes = new.changes(&current);
+    *current = Arc::new(new);
+    Ok(changes)
+}
+
+/// Re-reads the rules, keeping the old ones if the new ones are invalid.
+pub fn reload() -> Result<(), Error> {
+    for change in replace(&RULES, Rules::load())? {
+        eprintln!("pr-tracker: {}", change);
+    }
+    Ok(())
+}
+
+/// Reloads the rules whenever one of `signals` is received.
+pub async fn reload_on(mut signals: Signals) {
+    while signals.next().await.is_some() {
+        eprintln!("pr-tracker: reloading rules");
+        match reload() {
+            Ok(()) => eprintln!("pr-tracker: rules reloaded"),
+            Err(e) => eprintln!("pr-tracker: keeping previous rules: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::write;
+
+    #[test]
+    fn replacing() {
+        let folder = std::env::temp_dir().join(format!("pr-tracker-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let branch_config = folder.join("branches.toml");
+        let white_list = folder.join("white-list");
+        let read = || {
+            Rules::read(
+                [("nixpkgs", Some(branch_config.as_path()))],
+                Some(&white_list),
+            )
+        };
+
+        write(
+            &branch_config,
+            "[[next]]\npattern = '\\Aa\\z'\nnext = ['b']\n",
+        )
+        .unwrap();
+        write(&white_list, "a@example.com\n").unwrap();
+        let current = RwLock::new(Arc::new(read().unwrap()));
+        let describe = || current.read().unwrap().branches("nixpkgs").describe();
+        let before = describe();
+
+        // A file that doesn't parse keeps the rules that were there.
+        write(&branch_config, "[[next]]\npattern = '('\nnext = ['b']\n").unwrap();
+        assert!(matches!(
+            replace(&current, read()),
+            Err(Error::BranchConfig(..))
+        ));
+        assert_eq!(describe(), before);
+
+        // A good one replaces them, and what changed is logged.
+        write(
+            &branch_config,
+            "[[next]]\npattern = '\\Aa\\z'\nnext = ['c']\n",
+        )
+        .unwrap();
+        write(&white_list, "a@example.com\nB@Example.ORG\n").unwrap();
+        let changes = replace(&current, read()).unwrap();
+        assert_eq!(
+            changes,
+            [
+                "nixpkgs: removed branch rule: next \\Aa\\z -> b",
+                "nixpkgs: added branch rule: next \\Aa\\z -> c",
+                "white list: 1 added, 0 removed",
+            ]
+        );
+        assert_ne!(describe(), before);
+        let b = "B@example.org".parse().unwrap();
+        assert!(current.read().unwrap().white_list.contains(&b));
+
+        // White lists are read like subscribers' addresses, so ones
+        // that aren't any are refused.
+        write(&white_list, "a@example.com\nnot an address\n").unwrap();
+        assert!(matches!(
+            replace(&current, read()),
+            Err(Error::WhiteList(..))
+        ));
+        assert!(current.read().unwrap().white_list.contains(&b));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::time::Duration;
+
+use async_std::sync::Mutex;
+use async_std::task::sleep;
+use once_cell::sync::Lazy;
+
+use crate::{outbox, standing, store, update_subscribers, CONFIG};
+
+/// Held while subscribers are being updated, so that two updates never
+/// notify the same subscriber twice.
+static UPDATE_LOCK: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+/// Forgets unconfirmed subscriptions and unread feeds that have
+/// expired, subscribes standing subscribers to the PRs they've come to
+/// match, and updates subscribers, unless an update is already running.
+/// Then delivers the notifications that were queued.
+pub async fn update() {
+    let Some(guard) = UPDATE_LOCK.try_lock() else {
+        eprintln!("pr-tracker: skipping update, because one is already running");
+        return;
+    };
+
+    let period = Duration::from_secs(CONFIG.confirmation_period);
+    match store::store().prune_unconfirmed(period) {
+        Ok(0) => {}
+        Ok(n) => println!("Pruned {} unconfirmed subscriptions", n),
+        Err(e) => eprintln!("pr-tracker: pruning unconfirmed subscriptions: {}", e),
+    }
+
+    let retention = Duration::from_secs(CONFIG.feed_retention);
+    match store::store().prune_unwatched(retention) {
+        Ok(0) => {}
+        Ok(n) => println!("Stopped updating {} unread feeds", n),
+        Err(e) => eprintln!("pr-tracker: pruning unread feeds: {}", e),
+    }
+
+    if let Err(e) = standing::expand().await {
+        eprintln!("pr-tracker: expanding standing subscriptions: {}", e);
+    }
+    if let Err(e) = update_subscribers().await {
+        eprintln!("pr-tracker: updating subscribers: {}", e);
+    }
+    drop(guard);
+
+    outbox::deliver().await;
+}
+
+/// Updates subscribers every `interval`, plus up to `jitter`.
+pub async fn update_periodically(interval: Duration, jitter: Duration) {
+    loop {
+        let jitter = Duration::from_millis(fastrand::u64(0..=jitter.as_millis() as u64));
+        sleep(interval + jitter).await;
+        update().await;
+    }
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Turns standing subscriptions, to every PR that matches a filter,
+//! into subscriptions to each PR, which updates then track like any
+//! other.
+//!
+//! Only PRs that have been updated since the last search are looked
+//! for.  Merging a PR updates it, so no PR can reach a branch without
+//! being found, even if it was opened before the subscription.
+
+use std::time::SystemTime;
+
+use humantime::{format_rfc3339_seconds, parse_rfc3339};
+
+use crate::github;
+use crate::github::FoundPr;
+use crate::project::{Project, PROJECTS};
+use crate::store::{self, Standing};
+
+/// Whether a PR that a search found should be subscribed to.  PRs
+/// merged before the subscription was confirmed are left out, since
+/// they'd only be found because something else about them changed.
+fn matches(standing: &Standing, found: &FoundPr) -> bool {
+    let confirmed_at = format_rfc3339_seconds(standing.confirmed_at).to_string();
+    !found.closed
+        && found
+            .merged_at
+            .as_ref()
+            .is_none_or(|merged_at| *merged_at >= confirmed_at)
+}
+
+async fn expand_one(project: &Project, standing: &Standing) -> store::Result<()> {
+    // Anything updated while the search is running will be found
+    // again next time, which is harmless.
+    let started = SystemTime::now();
+    let since = standing.searched_at.unwrap_or(standing.confirmed_at);
+
+    let found = match github()
+        .search_prs(
+            &project.owner,
+            &project.repo,
+            &standing.filter,
+            &format_rfc3339_seconds(since).to_string(),
+        )
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!(
+                "pr-tracker: {}: searching for PRs {}: {}",
+                project.name, standing.filter, e
+            );
+            return Ok(());
+        }
+    };
+
+    // GitHub only gives so many results, least recently updated
+    // first, so if there were more, the next search carries on from
+    // the last PR this one found.
+    let searched_at = match found.prs.last() {
+        Some(last) if found.truncated => match parse_rfc3339(&last.updated_at) {
+            Ok(updated_at) => updated_at,
+            Err(_) => since,
+        },
+        _ => started,
+    };
+
+    let prs: Vec<_> = found
+        .prs
+        .iter()
+        .filter(|found| matches(standing, found))
+        .map(|found| found.number)
+        .collect();
+    for pr in store::store().expand(standing.id, &prs, searched_at)? {
+        println!(
+            "{} subscribed to {}#{} by {}",
+            standing.recipient, project.name, pr, standing.filter
+        );
+    }
+    Ok(())
+}
+
+/// Subscribes everyone with a standing subscription to the PRs it has
+/// come to match.
+pub async fn expand() -> store::Result<()> {
+    let store = store::store();
+    for project in PROJECTS.iter() {
+        for standing in store.standing(&project.name)? {
+            expand_one(project, &standing).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn matching() {
+        let standing = Standing {
+            id: 1,
+            recipient: "a@example.com".parse().unwrap(),
+            filter: "author:octocat".parse().unwrap(),
+            confirmed_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            searched_at: None,
+        };
+        let found = |merged_at: Option<&str>, closed| FoundPr {
+            number: 1.try_into().unwrap(),
+            merged_at: merged_at.map(String::from),
+            closed,
+            updated_at: "2023-11-14T22:13:20Z".to_string(),
+        };
+
+        assert!(matches(&standing, &found(None, false)));
+        assert!(!matches(&standing, &found(None, true)));
+        assert!(matches(
+            &standing,
+            &found(Some("2023-11-14T22:13:20Z"), false)
+        ));
+        assert!(!matches(
+            &standing,
+            &found(Some("2023-11-14T22:13:19Z"), false)
+        ));
+    }
+}
@@ -0,0 +1,2281 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read, read_dir};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::project::PROJECTS;
+use crate::tree::Branch;
+use crate::types::{Delivery, EmailAddress, Filter, MatrixId, PrNumber, Recipient};
+use crate::CONFIG;
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Io(PathBuf, io::Error),
+    Import(PathBuf, serde_json::Error),
+    /// The database was created by a newer version of pr-tracker.
+    UnknownVersion(i64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Sqlite(e) => write!(f, "SQLite: {}", e),
+            Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Import(path, e) => write!(f, "{}: {}", path.display(), e),
+            UnknownVersion(version) => write!(f, "unknown database version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Someone waiting to hear about the progress of a PR.
+#[derive(Debug)]
+pub struct Subscription {
+    pub recipient: Recipient,
+    /// The branches the subscriber already knows the PR has reached.
+    pub notified: HashSet<String>,
+    /// The branches the subscriber wants to hear about the PR
+    /// reaching, or empty for all of them.
+    pub targets: HashSet<String>,
+    /// The standing subscription that subscribed them to the PR, if
+    /// it's still there.
+    pub filter: Option<Filter>,
+}
+
+/// Something to tell a subscriber about a PR.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Notification {
+    pub project: String,
+    pub pr: PrNumber,
+    pub pr_title: String,
+    /// The branches the PR has newly reached, in tree order.
+    pub branches: Vec<Branch>,
+    /// Whether this is the last the subscriber will hear of the PR,
+    /// because it won't reach any more branches, or has reached all the
+    /// ones they're waiting for.
+    pub last: bool,
+    /// The standing subscription that subscribed them to the PR, which
+    /// they can unsubscribe from in the notification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+}
+
+/// Something to send a subscriber.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Message {
+    Notification(Notification),
+    /// Everything that's happened to a subscriber's PRs since their
+    /// last digest, with a notification for each PR.
+    Digest {
+        notifications: Vec<Notification>,
+    },
+}
+
+/// A message waiting in the outbox to be delivered.
+#[derive(Debug)]
+pub struct Queued {
+    pub id: i64,
+    pub recipient: Recipient,
+    pub message: Message,
+    /// How many times delivering it has failed so far.
+    pub attempts: u32,
+}
+
+/// What asking to subscribe did.
+#[derive(Debug, PartialEq)]
+pub enum Subscribing {
+    /// A pending subscription was added, and needs confirming.
+    Pending,
+    /// A subscription was already pending, and was asked for too
+    /// recently to send another confirmation.
+    StillPending,
+    /// The recipient was already subscribed.
+    Subscribed,
+}
+
+/// A branch a PR has reached, as shown in feeds.
+#[derive(Debug, PartialEq)]
+pub struct Reached {
+    pub project: String,
+    pub pr: PrNumber,
+    pub pr_title: String,
+    pub branch: Branch,
+    /// When the PR was first seen to have reached the branch.
+    pub reached_at: SystemTime,
+}
+
+/// One of a subscriber's subscriptions, as they see it.
+#[derive(Debug, PartialEq)]
+pub struct Subscribed {
+    pub project: String,
+    pub pr: PrNumber,
+    /// The branches they want to hear about the PR reaching, or empty
+    /// for all of them.
+    pub targets: HashSet<String>,
+}
+
+/// A subscription to every PR in a project that matches a filter.
+#[derive(Debug)]
+pub struct Standing {
+    pub id: i64,
+    pub recipient: Recipient,
+    pub filter: Filter,
+    /// PRs merged before then don't match.
+    pub confirmed_at: SystemTime,
+    /// When matching PRs were last searched for.
+    pub searched_at: Option<SystemTime>,
+}
+
+/// Where subscriptions to PRs are kept.
+pub trait SubscriptionStore: Send + Sync {
+    /// Adds a pending subscription of `recipient` to a PR, which is
+    /// known to have already reached `notified`, to hear about it
+    /// reaching `targets`, or every branch if that's empty.  Does
+    /// nothing if `recipient` is already subscribed to the PR, or asked
+    /// to be less than `resend_after` ago, so that asking over and over
+    /// can't be used to flood them with confirmations.  Otherwise,
+    /// subscribing again while still pending starts over.
+    fn subscribe(
+        &self,
+        project: &str,
+        pr: PrNumber,
+        recipient: &Recipient,
+        notified: &[String],
+        targets: &[String],
+        resend_after: Duration,
+    ) -> Result<Subscribing>;
+
+    /// Confirms a pending subscription, so that its subscriber starts
+    /// getting notifications.  Returns false if there's no such
+    /// subscription, perhaps because it was pruned.
+    fn confirm(&self, project: &str, pr: PrNumber, recipient: &Recipient) -> Result<bool>;
+
+    /// Removes pending subscriptions, including standing ones, that have
+    /// gone unconfirmed for longer than `period`, returning how many
+    /// were removed.
+    fn prune_unconfirmed(&self, period: Duration) -> Result<usize>;
+
+    fn unsubscribe(&self, project: &str, pr: PrNumber, recipient: &Recipient) -> Result<()>;
+
+    /// Unsubscribes `recipient` from every PR in every project.
+    fn unsubscribe_all(&self, recipient: &Recipient) -> Result<()>;
+
+    /// Returns how `recipient` wants to hear about their PRs, or `None`
+    /// if they've never subscribed to anything.
+    fn delivery(&self, recipient: &Recipient) -> Result<Option<Delivery>>;
+
+    /// Returns the confirmed subscriptions of `recipient`, ordered by
+    /// project and PR.
+    fn subscribed(&self, recipient: &Recipient) -> Result<Vec<Subscribed>>;
+
+    /// Changes the branches `recipient` wants to hear about a PR
+    /// reaching, like [`Self::subscribe`].  Returns false if they
+    /// aren't subscribed to it.
+    fn set_targets(
+        &self,
+        project: &str,
+        pr: PrNumber,
+        recipient: &Recipient,
+        targets: &[String],
+    ) -> Result<bool>;
+
+    /// Adds a pending standing subscription of `recipient` to every PR
+    /// in `project` that matches `filter`, like [`Self::subscribe`].
+    fn subscribe_standing(
+        &self,
+        project: &str,
+        filter: &Filter,
+        recipient: &Recipient,
+        resend_after: Duration,
+    ) -> Result<Subscribing>;
+
+    /// Confirms a pending standing subscription, like [`Self::confirm`].
+    fn confirm_standing(
+        &self,
+        project: &str,
+        filter: &Filter,
+        recipient: &Recipient,
+    ) -> Result<bool>;
+
+    /// Removes a standing subscription.  The PRs it has already been
+    /// expanded into are kept.
+    fn unsubscribe_standing(
+        &self,
+        project: &str,
+        filter: &Filter,
+        recipient: &Recipient,
+    ) -> Result<()>;
+
+    /// Returns the projects and filters of the confirmed standing
+    /// subscriptions of `recipient`.
+    fn standing_of(&self, recipient: &Recipient) -> Result<Vec<(String, Filter)>>;
+
+    /// Returns the confirmed standing subscriptions to PRs in
+    /// `project`, leaving out subscribers whose addresses have been
+    /// disabled.
+    fn standing(&self, project: &str) -> Result<Vec<Standing>>;
+
+    /// Subscribes a standing subscription's subscriber to `prs`, and
+    /// records that the next search can start at `searched_at`.  PRs are only
+    /// subscribed to the first time they're found, so unsubscribing
+    /// from one sticks.  Returns the PRs that were new.
+    fn expand(
+        &self,
+        standing: i64,
+        prs: &[PrNumber],
+        searched_at: SystemTime,
+    ) -> Result<Vec<PrNumber>>;
+
+    /// Returns the PRs in `project` with at least one confirmed
+    /// subscriber, or that are still to reach branches and have a feed
+    /// that's being read.
+    fn prs(&self, project: &str) -> Result<Vec<PrNumber>>;
+
+    /// Returns the confirmed subscriptions to a PR, leaving out
+    /// subscribers whose addresses have been disabled.
+    fn subscriptions(&self, project: &str, pr: PrNumber) -> Result<Vec<Subscription>>;
+
+    /// Sets how `recipient` wants to hear about all of their PRs.
+    fn set_delivery(&self, recipient: &Recipient, delivery: Delivery) -> Result<()>;
+
+    /// Forgets a PR and everyone subscribed to it, once there's
+    /// nothing left to tell them.
+    fn remove_pr(&self, project: &str, pr: PrNumber) -> Result<()>;
+}
+
+/// Where notifications wait to be delivered.
+pub trait OutboxStore: Send + Sync {
+    /// Puts `notification` in the outbox for `recipient`, and records that
+    /// they've been told about its branches, so that they're only
+    /// queued once.  If it's the last, their subscription is over.
+    ///
+    /// If `recipient` gets digests, it's kept for their next one
+    /// instead, replacing anything already kept about the PR, and its
+    /// branches only count as told once that's been collected.
+    fn queue(&self, recipient: &Recipient, notification: &Notification) -> Result<()>;
+
+    /// Puts a digest in the outbox for each subscriber whose oldest
+    /// kept notification has waited for as long as their delivery
+    /// preference says, returning how many there were.
+    fn collect_digests(&self) -> Result<usize>;
+
+    /// Returns up to `limit` messages from the outbox that are due to
+    /// be delivered, oldest first.
+    fn due(&self, limit: usize) -> Result<Vec<Queued>>;
+
+    /// Removes a message from the outbox, once it's been delivered, and
+    /// counts that as its subscriber's address working.
+    fn delivered(&self, id: i64) -> Result<()>;
+
+    /// Records a failed attempt to deliver a message, which will
+    /// be retried after `retry_in`.  Failures that are `permanent` count
+    /// against the subscriber's address; returns how many of those
+    /// there have been since a delivery last succeeded.
+    fn failed(&self, id: i64, error: &str, retry_in: Duration, permanent: bool) -> Result<u32>;
+
+    /// Drops the messages that have been failing to be delivered for
+    /// longer than `max_age`, however they failed, which counts once
+    /// against each of their subscribers' addresses.  Returns those
+    /// addresses, with how many failures each has had since a delivery
+    /// last succeeded.
+    fn expire(&self, max_age: Duration) -> Result<Vec<(Recipient, u32)>>;
+
+    /// Stops notifying `recipient`, and drops whatever is waiting to be
+    /// delivered to it or put in its next digest, until it confirms a subscription again.
+    fn disable(&self, recipient: &Recipient) -> Result<()>;
+}
+
+/// What the feeds of PRs are made from.
+pub trait FeedStore: Send + Sync {
+    /// Records that a PR has reached `branches`, keeping the time each
+    /// was first seen.  `last` is whether it won't reach any more.
+    fn record_reached(
+        &self,
+        project: &str,
+        pr: PrNumber,
+        pr_title: &str,
+        branches: &[Branch],
+        last: bool,
+    ) -> Result<()>;
+
+    /// Returns up to `limit` of the branches `prs` have reached, newest
+    /// first.
+    fn reached(&self, project: &str, prs: &[PrNumber], limit: usize) -> Result<Vec<Reached>>;
+
+    /// Records that a PR's feed has been read again, so that it's kept
+    /// up to date for longer.  Returns false if it isn't watched.
+    fn read(&self, project: &str, pr: PrNumber) -> Result<bool>;
+
+    /// Records that a PR's feed has been read, so that it's kept up to
+    /// date, unless `limit` PRs' feeds already are.  Returns whether
+    /// it's watched.
+    fn watch(&self, project: &str, pr: PrNumber, limit: usize) -> Result<bool>;
+
+    /// Stops updating the feeds of PRs that haven't been read for
+    /// longer than `period`, and forgets what they had reached unless
+    /// somebody's subscribed.  Returns how many were forgotten.
+    fn prune_unwatched(&self, period: Duration) -> Result<usize>;
+}
+
+/// What we need to remember to message people on Matrix.
+pub trait MatrixStore: Send + Sync {
+    /// Returns the room we last used to message a Matrix user.
+    fn direct_room(&self, user: &MatrixId) -> Result<Option<String>>;
+
+    /// Records the room to message a Matrix user in from now on.
+    fn set_direct_room(&self, user: &MatrixId, room: &str) -> Result<()>;
+}
+
+/// Everything pr-tracker keeps.
+pub trait Store: SubscriptionStore + OutboxStore + FeedStore + MatrixStore {}
+
+impl<T: SubscriptionStore + OutboxStore + FeedStore + MatrixStore> Store for T {}
+
+/// Seconds since the epoch, which is how times are stored.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Whether `time`, in seconds since the epoch, was less than `period`
+/// ago.
+fn is_recent(time: Option<i64>, period: Duration) -> bool {
+    time.is_some_and(|time| now().saturating_sub(time) < period.as_secs() as i64)
+}
+
+/// Each migration takes the database from the version before it to
+/// the next, with `PRAGMA user_version` recording how far we've got.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE subscribers (
+        id INTEGER PRIMARY KEY,
+        email TEXT NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE prs (
+        id INTEGER PRIMARY KEY,
+        project TEXT NOT NULL,
+        number INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        UNIQUE (project, number)
+    );
+
+    CREATE TABLE subscriptions (
+        subscriber INTEGER NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+        pr INTEGER NOT NULL REFERENCES prs (id) ON DELETE CASCADE,
+        created_at INTEGER NOT NULL,
+        notified_at INTEGER,
+        PRIMARY KEY (subscriber, pr)
+    );
+
+    CREATE TABLE notified_branches (
+        subscriber INTEGER NOT NULL,
+        pr INTEGER NOT NULL,
+        branch TEXT NOT NULL,
+        notified_at INTEGER NOT NULL,
+        PRIMARY KEY (subscriber, pr, branch),
+        FOREIGN KEY (subscriber, pr)
+            REFERENCES subscriptions (subscriber, pr) ON DELETE CASCADE
+    );
+",
+    "
+    -- Subscriptions from before confirmation was required count as
+    -- confirmed.
+    ALTER TABLE subscriptions ADD COLUMN confirmed_at INTEGER;
+    UPDATE subscriptions SET confirmed_at = created_at;
+",
+    // Email domains are lowercased when parsed, so stored ones have to
+    // be too.  Subscribers that only differed by the case of their
+    // domain are merged into one of them, the one that's already
+    // lowercase if there is one.  Addresses with quoted local parts,
+    // which can contain @ too, are rare enough to leave alone.
+    "
+    CREATE TEMPORARY TABLE email_addresses AS
+    SELECT id, email, substr(email, 1, instr(email, '@'))
+        || lower(substr(email, instr(email, '@') + 1)) AS normalized
+    FROM subscribers
+    WHERE instr(email, '@') > 1 AND email NOT LIKE '\"%';
+
+    CREATE TEMPORARY TABLE email_merges AS
+    SELECT old.id AS old, (
+        SELECT new.id FROM email_addresses AS new
+        WHERE new.normalized = old.normalized
+        ORDER BY new.email = new.normalized DESC, new.id
+        LIMIT 1
+    ) AS new
+    FROM email_addresses AS old;
+    DELETE FROM email_merges WHERE old = new;
+    DROP TABLE email_addresses;
+
+    INSERT OR IGNORE INTO subscriptions (subscriber, pr, created_at, notified_at, confirmed_at)
+    SELECT new, pr, created_at, notified_at, confirmed_at
+    FROM subscriptions JOIN email_merges ON old = subscriber;
+    INSERT OR IGNORE INTO notified_branches (subscriber, pr, branch, notified_at)
+    SELECT new, pr, branch, notified_at
+    FROM notified_branches JOIN email_merges ON old = subscriber;
+    DELETE FROM subscribers WHERE id IN (SELECT old FROM email_merges);
+    DROP TABLE email_merges;
+
+    UPDATE subscribers
+    SET email = substr(email, 1, instr(email, '@'))
+        || lower(substr(email, instr(email, '@') + 1))
+    WHERE instr(email, '@') > 1 AND email NOT LIKE '\"%';
+",
+    "
+    ALTER TABLE subscribers ADD COLUMN failures INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE subscribers ADD COLUMN disabled_at INTEGER;
+
+    -- Notifications aren't tied to rows in prs, because the last one
+    -- about a PR is still to be delivered after the PR is removed.
+    CREATE TABLE outbox (
+        id INTEGER PRIMARY KEY,
+        subscriber INTEGER NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+        project TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        notification TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT
+    );
+
+    CREATE INDEX outbox_next_attempt_at ON outbox (next_attempt_at);
+",
+    "
+    -- Subscribers can be webhooks as well as email addresses.
+    ALTER TABLE subscribers RENAME COLUMN email TO address;
+",
+    "
+    -- The rooms we've created to message Matrix users in, so that
+    -- they don't get a new one every time.
+    CREATE TABLE matrix_rooms (
+        user TEXT PRIMARY KEY,
+        room TEXT NOT NULL
+    );
+",
+    "
+    -- What feeds are made of.  Like the outbox, these aren't tied to
+    -- rows in prs, which only exist while there are subscribers.
+    CREATE TABLE reached_branches (
+        project TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        branch TEXT NOT NULL,
+        pr_title TEXT NOT NULL,
+        hydra_link TEXT,
+        reached_at INTEGER NOT NULL,
+        PRIMARY KEY (project, pr, branch)
+    );
+
+    CREATE TABLE watched_prs (
+        project TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        read_at INTEGER NOT NULL,
+        finished INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (project, pr)
+    );
+",
+    "
+    CREATE TABLE standing_subscriptions (
+        id INTEGER PRIMARY KEY,
+        subscriber INTEGER NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+        project TEXT NOT NULL,
+        filter TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        confirmed_at INTEGER,
+        searched_at INTEGER,
+        UNIQUE (subscriber, project, filter)
+    );
+
+    -- The PRs each standing subscription has been expanded into.
+    CREATE TABLE standing_matches (
+        standing INTEGER NOT NULL
+            REFERENCES standing_subscriptions (id) ON DELETE CASCADE,
+        pr INTEGER NOT NULL,
+        PRIMARY KEY (standing, pr)
+    );
+",
+    "
+    -- Subscriptions without any targets are to every branch.
+    CREATE TABLE subscription_targets (
+        subscriber INTEGER NOT NULL,
+        pr INTEGER NOT NULL,
+        branch TEXT NOT NULL,
+        PRIMARY KEY (subscriber, pr, branch),
+        FOREIGN KEY (subscriber, pr)
+            REFERENCES subscriptions (subscriber, pr) ON DELETE CASCADE
+    );
+",
+    "
+    ALTER TABLE subscribers ADD COLUMN delivery TEXT NOT NULL DEFAULT 'immediate';
+
+    -- Notifications kept for their subscriber's next digest, one for
+    -- each PR.
+    CREATE TABLE digest_items (
+        subscriber INTEGER NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+        project TEXT NOT NULL,
+        pr INTEGER NOT NULL,
+        notification TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (subscriber, project, pr)
+    );
+
+    -- Digests in the outbox aren't about any one PR.
+    CREATE TABLE new_outbox (
+        id INTEGER PRIMARY KEY,
+        subscriber INTEGER NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+        project TEXT,
+        pr INTEGER,
+        notification TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT
+    );
+    INSERT INTO new_outbox SELECT * FROM outbox;
+    DROP TABLE outbox;
+    ALTER TABLE new_outbox RENAME TO outbox;
+    CREATE INDEX outbox_next_attempt_at ON outbox (next_attempt_at);
+",
+];
+
+/// Keeps subscriptions in an SQLite database.
+pub struct Sqlite {
+    connection: Mutex<Connection>,
+}
+
+impl Sqlite {
+    /// Opens the database at `path`, creating it if necessary.  When
+    /// it's created, subscriptions are imported from `legacy`, the
+    /// folders that older versions kept them in.
+    pub fn open(path: &Path, legacy: &[(&str, PathBuf)]) -> Result<Self> {
+        Self::new(Connection::open(path)?, legacy)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory(legacy: &[(&str, PathBuf)]) -> Result<Self> {
+        Self::new(Connection::open_in_memory()?, legacy)
+    }
+
+    fn new(mut connection: Connection, legacy: &[(&str, PathBuf)]) -> Result<Self> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+
+        let transaction = connection.transaction()?;
+        let version: i64 =
+            transaction.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() as i64 {
+            return Err(Error::UnknownVersion(version));
+        }
+        for migration in &MIGRATIONS[version as usize..] {
+            transaction.execute_batch(migration)?;
+        }
+        if version == 0 {
+            for (project, folder) in legacy {
+                import(&transaction, project, folder)?;
+            }
+        }
+        transaction.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+        transaction.commit()?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn transaction<T>(&self, f: impl FnOnce(&Transaction) -> Result<T>) -> Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let result = f(&transaction)?;
+        transaction.commit()?;
+        Ok(result)
+    }
+}
+
+fn subscriber_id(transaction: &Transaction, recipient: &Recipient) -> Result<i64> {
+    transaction.execute(
+        "INSERT INTO subscribers (address, created_at) VALUES (?1, ?2)
+         ON CONFLICT (address) DO NOTHING",
+        params![recipient, now()],
+    )?;
+    Ok(transaction.query_row(
+        "SELECT id FROM subscribers WHERE address = ?1",
+        [recipient],
+        |row| row.get(0),
+    )?)
+}
+
+fn pr_id(transaction: &Transaction, project: &str, pr: PrNumber) -> Result<i64> {
+    transaction.execute(
+        "INSERT INTO prs (project, number, created_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (project, number) DO NOTHING",
+        params![project, pr, now()],
+    )?;
+    Ok(transaction.query_row(
+        "SELECT id FROM prs WHERE project = ?1 AND number = ?2",
+        params![project, pr],
+        |row| row.get(0),
+    )?)
+}
+
+fn subscribe(
+    transaction: &Transaction,
+    project: &str,
+    pr: PrNumber,
+    recipient: &Recipient,
+    notified: &[String],
+    targets: &[String],
+    confirmed: bool,
+) -> Result<bool> {
+    let subscriber = subscriber_id(transaction, recipient)?;
+    let pr = pr_id(transaction, project, pr)?;
+    let now = now();
+
+    let existing: Option<(Option<i64>, Option<i64>)> = transaction
+        .query_row(
+            "SELECT confirmed_at, disabled_at
+             FROM subscriptions JOIN subscribers ON subscribers.id = subscriber
+             WHERE subscriber = ?1 AND pr = ?2",
+            params![subscriber, pr],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    // Subscribing somebody who's already subscribed mustn't be a way
+    // to undo their confirmation, or to change what they've been
+    // told.  If their address has been disabled, they'll have to
+    // confirm it again anyway.
+    if let Some((Some(_), None)) = existing {
+        return Ok(false);
+    }
+
+    transaction.execute(
+        "DELETE FROM subscriptions WHERE subscriber = ?1 AND pr = ?2",
+        params![subscriber, pr],
+    )?;
+    transaction.execute(
+        "INSERT INTO subscriptions (subscriber, pr, created_at, confirmed_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![subscriber, pr, now, confirmed.then_some(now)],
+    )?;
+    let mut insert = transaction.prepare(
+        "INSERT OR IGNORE INTO notified_branches (subscriber, pr, branch, notified_at)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for branch in notified {
+        insert.execute(params![subscriber, pr, branch, now])?;
+    }
+    let mut insert = transaction.prepare(
+        "INSERT OR IGNORE INTO subscription_targets (subscriber, pr, branch)
+         VALUES (?1, ?2, ?3)",
+    )?;
+    for branch in targets {
+        insert.execute(params![subscriber, pr, branch])?;
+    }
+
+    Ok(true)
+}
+
+/// Removes PRs and subscribers that no longer have any subscriptions,
+/// standing or otherwise, or anything left to send them, so that we
+/// don't hold on to addresses we don't need.
+fn remove_orphans(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "DELETE FROM prs WHERE id NOT IN (SELECT pr FROM subscriptions);
+         DELETE FROM subscribers
+         WHERE id NOT IN (SELECT subscriber FROM subscriptions)
+         AND id NOT IN (SELECT subscriber FROM standing_subscriptions)
+         AND id NOT IN (SELECT subscriber FROM outbox)
+         AND id NOT IN (SELECT subscriber FROM digest_items);
+         DELETE FROM matrix_rooms WHERE user NOT IN (SELECT address FROM subscribers);",
+    )?;
+    Ok(())
+}
+
+/// Reads JSON from column `index` of `row`.
+fn json<T: DeserializeOwned>(row: &rusqlite::Row, index: usize) -> rusqlite::Result<T> {
+    let json: String = row.get(index)?;
+    serde_json::from_str(&json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+/// Reads a row of the outbox.
+fn queued(row: &rusqlite::Row) -> rusqlite::Result<Queued> {
+    Ok(Queued {
+        id: row.get(0)?,
+        recipient: row.get(1)?,
+        message: json(row, 2)?,
+        attempts: row.get(3)?,
+    })
+}
+
+/// Imports the subscriptions to `project` from `folder`, which holds a
+/// folder for each PR containing a file for each subscriber, which
+/// lists the branches they've been notified of.
+fn import(transaction: &Transaction, project: &str, folder: &Path) -> Result<()> {
+    let entries = match read_dir(folder) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::Io(folder.to_path_buf(), e)),
+    };
+
+    for entry in entries {
+        let dir_path = entry
+            .map_err(|e| Error::Io(folder.to_path_buf(), e))?
+            .path();
+        let Some(pr) = dir_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<PrNumber>().ok())
+        else {
+            continue;
+        };
+        if !dir_path.is_dir() {
+            continue;
+        }
+
+        for entry in read_dir(&dir_path).map_err(|e| Error::Io(dir_path.clone(), e))? {
+            let file_path = entry.map_err(|e| Error::Io(dir_path.clone(), e))?.path();
+            let Some(email) = file_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<EmailAddress>().ok())
+            else {
+                continue;
+            };
+            if !file_path.is_file() {
+                continue;
+            }
+
+            let contents = read(&file_path).map_err(|e| Error::Io(file_path.clone(), e))?;
+            let notified: Vec<String> = serde_json::from_slice(&contents)
+                .map_err(|e| Error::Import(file_path.clone(), e))?;
+            subscribe(
+                transaction,
+                project,
+                pr,
+                &email.into(),
+                &notified,
+                &[],
+                true,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+impl SubscriptionStore for Sqlite {
+    fn subscribe(
+        &self,
+        project: &str,
+        pr: PrNumber,
+        recipient: &Recipient,
+        notified: &[String],
+        targets: &[String],
+        resend_after: Duration,
+    ) -> Result<Subscribing> {
+        self.transaction(|transaction| {
+            let pending_since: Option<i64> = transaction
+                .query_row(
+                    "SELECT subscriptions.created_at
+                     FROM subscriptions JOIN prs ON prs.id = pr
+                     WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                     AND project = ?2 AND number = ?3 AND confirmed_at IS NULL",
+                    params![recipient, project, pr],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if is_recent(pending_since, resend_after) {
+                return Ok(Subscribing::StillPending);
+            }
+
+            let pending = subscribe(
+                transaction,
+                project,
+                pr,
+                recipient,
+                notified,
+                targets,
+                false,
+            )?;
+            Ok(if pending {
+                Subscribing::Pending
+            } else {
+                Subscribing::Subscribed
+            })
+        })
+    }
+
+    fn confirm(&self, project: &str, pr: PrNumber, recipient: &Recipient) -> Result<bool> {
+        self.transaction(|transaction| {
+            let changed = transaction.execute(
+                "UPDATE subscriptions SET confirmed_at = ?4
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND pr = (SELECT id FROM prs WHERE project = ?2 AND number = ?3)
+                 AND confirmed_at IS NULL",
+                params![recipient, project, pr, now()],
+            )?;
+            if changed > 0 {
+                // Confirming shows that their address works.
+                transaction.execute(
+                    "UPDATE subscribers SET failures = 0, disabled_at = NULL WHERE address = ?1",
+                    [recipient],
+                )?;
+                return Ok(true);
+            }
+
+            // Following the link twice is fine.
+            let confirmed = transaction
+                .query_row(
+                    "SELECT 1 FROM subscriptions
+                     WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                     AND pr = (SELECT id FROM prs WHERE project = ?2 AND number = ?3)",
+                    params![recipient, project, pr],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(confirmed.is_some())
+        })
+    }
+
+    fn prune_unconfirmed(&self, period: Duration) -> Result<usize> {
+        self.transaction(|transaction| {
+            let cutoff = now() - period.as_secs() as i64;
+            let removed = transaction.execute(
+                "DELETE FROM subscriptions WHERE confirmed_at IS NULL AND created_at < ?1",
+                [cutoff],
+            )? + transaction.execute(
+                "DELETE FROM standing_subscriptions
+                 WHERE confirmed_at IS NULL AND created_at < ?1",
+                [cutoff],
+            )?;
+            remove_orphans(transaction)?;
+            Ok(removed)
+        })
+    }
+
+    fn unsubscribe(&self, project: &str, pr: PrNumber, recipient: &Recipient) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "DELETE FROM subscriptions
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND pr = (SELECT id FROM prs WHERE project = ?2 AND number = ?3)",
+                params![recipient, project, pr],
+            )?;
+            transaction.execute(
+                "DELETE FROM outbox
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND project = ?2 AND pr = ?3",
+                params![recipient, project, pr],
+            )?;
+            transaction.execute(
+                "DELETE FROM digest_items
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND project = ?2 AND pr = ?3",
+                params![recipient, project, pr],
+            )?;
+            remove_orphans(transaction)
+        })
+    }
+
+    fn unsubscribe_all(&self, recipient: &Recipient) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute("DELETE FROM subscribers WHERE address = ?1", [recipient])?;
+            remove_orphans(transaction)
+        })
+    }
+
+    fn delivery(&self, recipient: &Recipient) -> Result<Option<Delivery>> {
+        self.transaction(|transaction| {
+            Ok(transaction
+                .query_row(
+                    "SELECT delivery FROM subscribers WHERE address = ?1",
+                    [recipient],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+    }
+
+    fn subscribed(&self, recipient: &Recipient) -> Result<Vec<Subscribed>> {
+        self.transaction(|transaction| {
+            let mut statement = transaction.prepare(
+                "SELECT subscriber, pr, project, number
+                 FROM subscriptions JOIN prs ON prs.id = pr
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND confirmed_at IS NOT NULL
+                 ORDER BY project, number",
+            )?;
+            let mut targets = transaction.prepare(
+                "SELECT branch FROM subscription_targets WHERE subscriber = ?1 AND pr = ?2",
+            )?;
+
+            let rows = statement
+                .query_map([recipient], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut subscribed = Vec::new();
+            for (subscriber, pr_id, project, pr) in rows {
+                subscribed.push(Subscribed {
+                    project,
+                    pr,
+                    targets: targets
+                        .query_map(params![subscriber, pr_id], |row| row.get(0))?
+                        .collect::<Result<_, _>>()?,
+                });
+            }
+            Ok(subscribed)
+        })
+    }
+
+    fn set_targets(
+        &self,
+        project: &str,
+        pr: PrNumber,
+        recipient: &Recipient,
+        targets: &[String],
+    ) -> Result<bool> {
+        self.transaction(|transaction| {
+            let subscription = transaction
+                .query_row(
+                    "SELECT subscriber, pr FROM subscriptions
+                     WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                     AND pr = (SELECT id FROM prs WHERE project = ?2 AND number = ?3)",
+                    params![recipient, project, pr],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()?;
+            let Some((subscriber, pr)) = subscription else {
+                return Ok(false);
+            };
+
+            transaction.execute(
+                "DELETE FROM subscription_targets WHERE subscriber = ?1 AND pr = ?2",
+                params![subscriber, pr],
+            )?;
+            let mut insert = transaction.prepare(
+                "INSERT OR IGNORE INTO subscription_targets (subscriber, pr, branch)
+                 VALUES (?1, ?2, ?3)",
+            )?;
+            for branch in targets {
+                insert.execute(params![subscriber, pr, branch])?;
+            }
+            Ok(true)
+        })
+    }
+
+    fn subscribe_standing(
+        &self,
+        project: &str,
+        filter: &Filter,
+        recipient: &Recipient,
+        resend_after: Duration,
+    ) -> Result<Subscribing> {
+        self.transaction(|transaction| {
+            let subscriber = subscriber_id(transaction, recipient)?;
+            let existing: Option<(Option<i64>, Option<i64>, i64)> = transaction
+                .query_row(
+                    "SELECT confirmed_at, disabled_at, standing_subscriptions.created_at
+                     FROM standing_subscriptions
+                     JOIN subscribers ON subscribers.id = subscriber
+                     WHERE subscriber = ?1 AND project = ?2 AND filter = ?3",
+                    params![subscriber, project, filter],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()?;
+            match existing {
+                Some((Some(_), None, _)) => return Ok(Subscribing::Subscribed),
+                Some((None, _, created_at)) if is_recent(Some(created_at), resend_after) => {
+                    return Ok(Subscribing::StillPending)
+                }
+                _ => {}
+            }
+
+            // Starting over forgets what was matched before, which is
+            // fine, since nothing has been matched while pending.
+            transaction.execute(
+                "DELETE FROM standing_subscriptions
+                 WHERE subscriber = ?1 AND project = ?2 AND filter = ?3",
+                params![subscriber, project, filter],
+            )?;
+            transaction.execute(
+                "INSERT INTO standing_subscriptions (subscriber, project, filter, created_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![subscriber, project, filter, now()],
+            )?;
+            Ok(Subscribing::Pending)
+        })
+    }
+
+    fn confirm_standing(
+        &self,
+        project: &str,
+        filter: &Filter,
+        recipient: &Recipient,
+    ) -> Result<bool> {
+        self.transaction(|transaction| {
+            let changed = transaction.execute(
+                "UPDATE standing_subscriptions SET confirmed_at = ?4
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND project = ?2 AND filter = ?3
+                 AND confirmed_at IS NULL",
+                params![recipient, project, filter, now()],
+            )?;
+            if changed > 0 {
+                transaction.execute(
+                    "UPDATE subscribers SET failures = 0, disabled_at = NULL WHERE address = ?1",
+                    [recipient],
+                )?;
+                return Ok(true);
+            }
+
+            let confirmed = transaction
+                .query_row(
+                    "SELECT 1 FROM standing_subscriptions
+                     WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                     AND project = ?2 AND filter = ?3",
+                    params![recipient, project, filter],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(confirmed.is_some())
+        })
+    }
+
+    fn unsubscribe_standing(
+        &self,
+        project: &str,
+        filter: &Filter,
+        recipient: &Recipient,
+    ) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "DELETE FROM standing_subscriptions
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND project = ?2 AND filter = ?3",
+                params![recipient, project, filter],
+            )?;
+            remove_orphans(transaction)
+        })
+    }
+
+    fn standing_of(&self, recipient: &Recipient) -> Result<Vec<(String, Filter)>> {
+        self.transaction(|transaction| {
+            let mut statement = transaction.prepare(
+                "SELECT project, filter FROM standing_subscriptions
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)
+                 AND confirmed_at IS NOT NULL
+                 ORDER BY project, filter",
+            )?;
+            let standing = statement
+                .query_map([recipient], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            Ok(standing)
+        })
+    }
+
+    fn standing(&self, project: &str) -> Result<Vec<Standing>> {
+        self.transaction(|transaction| {
+            let mut statement = transaction.prepare(
+                "SELECT standing_subscriptions.id, address, filter, confirmed_at, searched_at
+                 FROM standing_subscriptions JOIN subscribers ON subscribers.id = subscriber
+                 WHERE project = ?1 AND confirmed_at IS NOT NULL AND disabled_at IS NULL
+                 ORDER BY standing_subscriptions.id",
+            )?;
+            let time = |seconds: i64| UNIX_EPOCH + Duration::from_secs(seconds as u64);
+            let standing = statement
+                .query_map([project], |row| {
+                    Ok(Standing {
+                        id: row.get(0)?,
+                        recipient: row.get(1)?,
+                        filter: row.get(2)?,
+                        confirmed_at: time(row.get(3)?),
+                        searched_at: row.get::<_, Option<i64>>(4)?.map(time),
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(standing)
+        })
+    }
+
+    fn expand(
+        &self,
+        standing: i64,
+        prs: &[PrNumber],
+        searched_at: SystemTime,
+    ) -> Result<Vec<PrNumber>> {
+        self.transaction(|transaction| {
+            let subscription = transaction
+                .query_row(
+                    "SELECT project, address
+                     FROM standing_subscriptions JOIN subscribers ON subscribers.id = subscriber
+                     WHERE standing_subscriptions.id = ?1",
+                    [standing],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, Recipient>(1)?)),
+                )
+                .optional()?;
+            // They might have unsubscribed during the search.
+            let Some((project, recipient)) = subscription else {
+                return Ok(Vec::new());
+            };
+
+            let mut new = Vec::new();
+            for &pr in prs {
+                let inserted = transaction.execute(
+                    "INSERT OR IGNORE INTO standing_matches (standing, pr) VALUES (?1, ?2)",
+                    params![standing, pr],
+                )?;
+                if inserted > 0 && subscribe(transaction, &project, pr, &recipient, &[], &[], true)?
+                {
+                    new.push(pr);
+                }
+            }
+
+            let searched_at = searched_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default();
+            transaction.execute(
+                "UPDATE standing_subscriptions SET searched_at = ?2 WHERE id = ?1",
+                params![standing, searched_at],
+            )?;
+            Ok(new)
+        })
+    }
+
+    fn prs(&self, project: &str) -> Result<Vec<PrNumber>> {
+        self.transaction(|transaction| {
+            let mut statement = transaction.prepare(
+                "SELECT number FROM prs
+                 WHERE project = ?1
+                 AND id IN (
+                     SELECT pr FROM subscriptions JOIN subscribers ON subscribers.id = subscriber
+                     WHERE confirmed_at IS NOT NULL AND disabled_at IS NULL
+                 )
+                 UNION
+                 SELECT pr FROM watched_prs WHERE project = ?1 AND NOT finished
+                 ORDER BY number",
+            )?;
+            let prs = statement
+                .query_map([project], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(prs)
+        })
+    }
+
+    fn subscriptions(&self, project: &str, number: PrNumber) -> Result<Vec<Subscription>> {
+        self.transaction(|transaction| {
+            let Some(pr) = transaction
+                .query_row(
+                    "SELECT id FROM prs WHERE project = ?1 AND number = ?2",
+                    params![project, number],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?
+            else {
+                return Ok(Vec::new());
+            };
+
+            let mut statement = transaction.prepare(
+                "SELECT subscribers.id, address
+                 FROM subscriptions JOIN subscribers ON subscribers.id = subscriber
+                 WHERE pr = ?1 AND confirmed_at IS NOT NULL AND disabled_at IS NULL
+                 ORDER BY address",
+            )?;
+            let mut branches = transaction.prepare(
+                "SELECT branch FROM notified_branches WHERE subscriber = ?1 AND pr = ?2",
+            )?;
+            let mut targets = transaction.prepare(
+                "SELECT branch FROM subscription_targets WHERE subscriber = ?1 AND pr = ?2",
+            )?;
+            let mut filters = transaction.prepare(
+                "SELECT filter
+                 FROM standing_matches JOIN standing_subscriptions ON id = standing
+                 WHERE subscriber = ?1 AND project = ?2 AND standing_matches.pr = ?3
+                 AND confirmed_at IS NOT NULL
+                 ORDER BY filter
+                 LIMIT 1",
+            )?;
+
+            let rows = statement
+                .query_map([pr], |row| Ok((row.get::<_, i64>(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut subscriptions = Vec::new();
+            for (subscriber, recipient) in rows {
+                let notified = branches
+                    .query_map(params![subscriber, pr], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?;
+                let targets = targets
+                    .query_map(params![subscriber, pr], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?;
+                let filter = filters
+                    .query_row(params![subscriber, project, number], |row| row.get(0))
+                    .optional()?;
+                subscriptions.push(Subscription {
+                    recipient,
+                    notified,
+                    targets,
+                    filter,
+                });
+            }
+
+            Ok(subscriptions)
+        })
+    }
+
+    fn set_delivery(&self, recipient: &Recipient, delivery: Delivery) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "UPDATE subscribers SET delivery = ?2 WHERE address = ?1",
+                params![recipient, delivery],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn remove_pr(&self, project: &str, pr: PrNumber) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "DELETE FROM prs WHERE project = ?1 AND number = ?2",
+                params![project, pr],
+            )?;
+            remove_orphans(transaction)
+        })
+    }
+}
+
+impl OutboxStore for Sqlite {
+    fn queue(&self, recipient: &Recipient, notification: &Notification) -> Result<()> {
+        self.transaction(|transaction| {
+            let now = now();
+            let subscription = transaction
+                .query_row(
+                    "SELECT subscriber, pr, delivery
+                     FROM subscriptions JOIN subscribers ON subscribers.id = subscriber
+                     WHERE address = ?1
+                     AND pr = (SELECT id FROM prs WHERE project = ?2 AND number = ?3)",
+                    params![recipient, notification.project, notification.pr],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, Delivery>(2)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            // They might have unsubscribed while they were being
+            // notified.
+            let Some((subscriber, pr, delivery)) = subscription else {
+                return Ok(());
+            };
+
+            transaction.execute(
+                "UPDATE subscriptions SET notified_at = ?3 WHERE subscriber = ?1 AND pr = ?2",
+                params![subscriber, pr, now],
+            )?;
+            if delivery == Delivery::Immediate {
+                let mut insert = transaction.prepare(
+                    "INSERT OR IGNORE INTO notified_branches (subscriber, pr, branch, notified_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for branch in &notification.branches {
+                    insert.execute(params![subscriber, pr, branch.name, now])?;
+                }
+
+                transaction.execute(
+                    "INSERT INTO outbox
+                     (subscriber, project, pr, notification, created_at, next_attempt_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+                    params![
+                        subscriber,
+                        notification.project,
+                        notification.pr,
+                        serde_json::to_string(notification).unwrap(),
+                        now,
+                    ],
+                )?;
+            } else {
+                // Until the digest is collected, the next update finds
+                // these branches again, along with any new ones, so
+                // that this is replaced by everything in tree order.
+                transaction.execute(
+                    "INSERT INTO digest_items (subscriber, project, pr, notification, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (subscriber, project, pr)
+                     DO UPDATE SET notification = excluded.notification",
+                    params![
+                        subscriber,
+                        notification.project,
+                        notification.pr,
+                        serde_json::to_string(notification).unwrap(),
+                        now,
+                    ],
+                )?;
+            }
+            if notification.last {
+                transaction.execute(
+                    "DELETE FROM subscriptions WHERE subscriber = ?1 AND pr = ?2",
+                    params![subscriber, pr],
+                )?;
+                remove_orphans(transaction)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn collect_digests(&self) -> Result<usize> {
+        self.transaction(|transaction| {
+            let now = now();
+            let mut statement = transaction.prepare(
+                "SELECT subscriber, delivery, min(digest_items.created_at)
+                 FROM digest_items JOIN subscribers ON subscribers.id = subscriber
+                 WHERE disabled_at IS NULL
+                 GROUP BY subscriber",
+            )?;
+            let due: Vec<i64> = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Delivery>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                })?
+                .filter(|row| {
+                    row.as_ref().map_or(true, |&(_, delivery, oldest)| {
+                        oldest + delivery.period().as_secs() as i64 <= now
+                    })
+                })
+                .map(|row| row.map(|(subscriber, _, _)| subscriber))
+                .collect::<Result<_, _>>()?;
+
+            let mut items = transaction.prepare(
+                "SELECT notification FROM digest_items WHERE subscriber = ?1
+                 ORDER BY project, pr",
+            )?;
+            let mut notified = transaction.prepare(
+                "INSERT OR IGNORE INTO notified_branches (subscriber, pr, branch, notified_at)
+                 SELECT subscriber, pr, ?4, ?5
+                 FROM subscriptions JOIN prs ON prs.id = pr
+                 WHERE subscriber = ?1 AND project = ?2 AND number = ?3",
+            )?;
+            for &subscriber in &due {
+                let notifications: Vec<Notification> = items
+                    .query_map([subscriber], |row| json(row, 0))?
+                    .collect::<Result<_, _>>()?;
+                for notification in &notifications {
+                    for branch in &notification.branches {
+                        notified.execute(params![
+                            subscriber,
+                            notification.project,
+                            notification.pr,
+                            branch.name,
+                            now
+                        ])?;
+                    }
+                }
+
+                transaction.execute(
+                    "INSERT INTO outbox (subscriber, notification, created_at, next_attempt_at)
+                     VALUES (?1, ?2, ?3, ?3)",
+                    params![
+                        subscriber,
+                        serde_json::to_string(&Message::Digest { notifications }).unwrap(),
+                        now,
+                    ],
+                )?;
+                transaction.execute(
+                    "DELETE FROM digest_items WHERE subscriber = ?1",
+                    [subscriber],
+                )?;
+            }
+            Ok(due.len())
+        })
+    }
+
+    fn due(&self, limit: usize) -> Result<Vec<Queued>> {
+        self.transaction(|transaction| {
+            let mut statement = transaction.prepare(
+                "SELECT outbox.id, address, notification, attempts
+                 FROM outbox JOIN subscribers ON subscribers.id = subscriber
+                 WHERE next_attempt_at <= ?1 AND disabled_at IS NULL
+                 ORDER BY outbox.id
+                 LIMIT ?2",
+            )?;
+            let queued = statement
+                .query_map(params![now(), limit], queued)?
+                .collect::<Result<_, _>>()?;
+            Ok(queued)
+        })
+    }
+
+    fn delivered(&self, id: i64) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "UPDATE subscribers SET failures = 0
+                 WHERE id = (SELECT subscriber FROM outbox WHERE id = ?1)",
+                [id],
+            )?;
+            transaction.execute("DELETE FROM outbox WHERE id = ?1", [id])?;
+            remove_orphans(transaction)
+        })
+    }
+
+    fn failed(&self, id: i64, error: &str, retry_in: Duration, permanent: bool) -> Result<u32> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "UPDATE outbox
+                 SET attempts = attempts + 1, last_error = ?2, next_attempt_at = ?3
+                 WHERE id = ?1",
+                params![id, error, now() + retry_in.as_secs() as i64],
+            )?;
+            if permanent {
+                transaction.execute(
+                    "UPDATE subscribers SET failures = failures + 1
+                     WHERE id = (SELECT subscriber FROM outbox WHERE id = ?1)",
+                    [id],
+                )?;
+            }
+            let failures = transaction
+                .query_row(
+                    "SELECT failures FROM subscribers
+                     WHERE id = (SELECT subscriber FROM outbox WHERE id = ?1)",
+                    [id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(failures.unwrap_or_default())
+        })
+    }
+
+    fn expire(&self, max_age: Duration) -> Result<Vec<(Recipient, u32)>> {
+        self.transaction(|transaction| {
+            let before = now() - max_age.as_secs() as i64;
+            transaction.execute(
+                "UPDATE subscribers SET failures = failures + 1
+                 WHERE id IN (SELECT subscriber FROM outbox
+                              WHERE created_at <= ?1 AND attempts > 0)",
+                [before],
+            )?;
+            let expired = transaction
+                .prepare(
+                    "SELECT address, failures FROM subscribers
+                     WHERE id IN (SELECT subscriber FROM outbox
+                                  WHERE created_at <= ?1 AND attempts > 0)
+                     ORDER BY address",
+                )?
+                .query_map([before], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            transaction.execute(
+                "DELETE FROM outbox WHERE created_at <= ?1 AND attempts > 0",
+                [before],
+            )?;
+            remove_orphans(transaction)?;
+            Ok(expired)
+        })
+    }
+
+    fn disable(&self, recipient: &Recipient) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "UPDATE subscribers SET disabled_at = ?2 WHERE address = ?1",
+                params![recipient, now()],
+            )?;
+            transaction.execute(
+                "DELETE FROM outbox
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)",
+                [recipient],
+            )?;
+            transaction.execute(
+                "DELETE FROM digest_items
+                 WHERE subscriber = (SELECT id FROM subscribers WHERE address = ?1)",
+                [recipient],
+            )?;
+            remove_orphans(transaction)
+        })
+    }
+}
+
+impl FeedStore for Sqlite {
+    fn record_reached(
+        &self,
+        project: &str,
+        pr: PrNumber,
+        pr_title: &str,
+        branches: &[Branch],
+        last: bool,
+    ) -> Result<()> {
+        self.transaction(|transaction| {
+            let now = now();
+            let mut insert = transaction.prepare(
+                "INSERT OR IGNORE INTO reached_branches
+                 (project, pr, branch, pr_title, hydra_link, reached_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for branch in branches {
+                insert.execute(params![
+                    project,
+                    pr,
+                    branch.name,
+                    pr_title,
+                    branch.hydra_link,
+                    now
+                ])?;
+            }
+            transaction.execute(
+                "UPDATE watched_prs SET finished = ?3 WHERE project = ?1 AND pr = ?2",
+                params![project, pr, last],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn reached(&self, project: &str, prs: &[PrNumber], limit: usize) -> Result<Vec<Reached>> {
+        self.transaction(|transaction| {
+            let mut statement = transaction.prepare(
+                "SELECT pr, pr_title, branch, hydra_link, reached_at
+                 FROM reached_branches
+                 WHERE project = ?1 AND pr IN (SELECT value FROM json_each(?2))
+                 ORDER BY reached_at DESC, rowid DESC
+                 LIMIT ?3",
+            )?;
+            let prs = serde_json::to_string(prs).unwrap();
+            let reached = statement
+                .query_map(params![project, prs, limit], |row| {
+                    Ok(Reached {
+                        project: project.to_string(),
+                        pr: row.get(0)?,
+                        pr_title: row.get(1)?,
+                        branch: Branch {
+                            name: row.get(2)?,
+                            hydra_link: row.get(3)?,
+                        },
+                        reached_at: UNIX_EPOCH + Duration::from_secs(row.get::<_, i64>(4)? as u64),
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(reached)
+        })
+    }
+
+    fn read(&self, project: &str, pr: PrNumber) -> Result<bool> {
+        self.transaction(|transaction| {
+            let updated = transaction.execute(
+                "UPDATE watched_prs SET read_at = ?3 WHERE project = ?1 AND pr = ?2",
+                params![project, pr, now()],
+            )?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn watch(&self, project: &str, pr: PrNumber, limit: usize) -> Result<bool> {
+        self.transaction(|transaction| {
+            let watched: bool = transaction.query_row(
+                "SELECT EXISTS (SELECT 1 FROM watched_prs WHERE project = ?1 AND pr = ?2)",
+                params![project, pr],
+                |row| row.get(0),
+            )?;
+            if !watched {
+                let count: usize =
+                    transaction
+                        .query_row("SELECT count(*) FROM watched_prs", [], |row| row.get(0))?;
+                if count >= limit {
+                    return Ok(false);
+                }
+            }
+            transaction.execute(
+                "INSERT INTO watched_prs (project, pr, read_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (project, pr) DO UPDATE SET read_at = excluded.read_at",
+                params![project, pr, now()],
+            )?;
+            Ok(true)
+        })
+    }
+
+    fn prune_unwatched(&self, period: Duration) -> Result<usize> {
+        self.transaction(|transaction| {
+            let cutoff = now() - period.as_secs() as i64;
+            let pruned =
+                transaction.execute("DELETE FROM watched_prs WHERE read_at < ?1", [cutoff])?;
+            transaction.execute(
+                "DELETE FROM reached_branches
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM watched_prs
+                     WHERE watched_prs.project = reached_branches.project
+                     AND watched_prs.pr = reached_branches.pr
+                 )
+                 AND NOT EXISTS (
+                     SELECT 1 FROM prs
+                     WHERE prs.project = reached_branches.project
+                     AND prs.number = reached_branches.pr
+                 )",
+                [],
+            )?;
+            Ok(pruned)
+        })
+    }
+}
+
+impl MatrixStore for Sqlite {
+    fn direct_room(&self, user: &MatrixId) -> Result<Option<String>> {
+        self.transaction(|transaction| {
+            Ok(transaction
+                .query_row(
+                    "SELECT room FROM matrix_rooms WHERE user = ?1",
+                    [user],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+    }
+
+    fn set_direct_room(&self, user: &MatrixId, room: &str) -> Result<()> {
+        self.transaction(|transaction| {
+            transaction.execute(
+                "INSERT INTO matrix_rooms (user, room) VALUES (?1, ?2)
+                 ON CONFLICT (user) DO UPDATE SET room = excluded.room",
+                params![user, room],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+static STORE: Lazy<Box<dyn Store>> = Lazy::new(|| {
+    let legacy: Vec<_> = PROJECTS
+        .iter()
+        .map(|project| (project.name.as_str(), project.data_folder()))
+        .collect();
+
+    match Sqlite::open(&CONFIG.database, &legacy) {
+        Ok(store) => Box::new(store),
+        Err(e) => {
+            eprintln!("pr-tracker: {}: {}", CONFIG.database.display(), e);
+            std::process::exit(74);
+        }
+    }
+});
+
+/// Returns where everything is kept.
+///
+/// Its methods block, and are called straight from async tasks rather
+/// than with `spawn_blocking`.  Each is a single short transaction on a
+/// local SQLite file, like the blocking reads of the PR cache, with
+/// nothing that waits on the network done while the connection is
+/// held, so a thread hop for every call would cost more than it saves.
+pub fn store() -> &'static dyn Store {
+    STORE.as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn recipients(subscriptions: &[Subscription]) -> Vec<&str> {
+        subscriptions.iter().map(|s| s.recipient.as_str()).collect()
+    }
+
+    fn a() -> Recipient {
+        "a@example.com".parse().unwrap()
+    }
+
+    fn b() -> Recipient {
+        "b@example.com".parse().unwrap()
+    }
+
+    fn pr(number: i64) -> PrNumber {
+        number.try_into().unwrap()
+    }
+
+    fn numbers(prs: Vec<PrNumber>) -> Vec<i64> {
+        prs.into_iter().map(PrNumber::get).collect()
+    }
+
+    fn notification(number: i64, last: bool) -> Notification {
+        Notification {
+            project: "nixpkgs".to_string(),
+            pr: pr(number),
+            pr_title: "title".to_string(),
+            branches: vec![Branch {
+                name: "staging".to_string(),
+                hydra_link: None,
+            }],
+            last,
+            filter: None,
+        }
+    }
+
+    fn subscribe(
+        store: &Sqlite,
+        project: &str,
+        number: i64,
+        recipient: &Recipient,
+        notified: &[String],
+    ) {
+        assert_eq!(
+            store
+                .subscribe(
+                    project,
+                    pr(number),
+                    recipient,
+                    notified,
+                    &[],
+                    Duration::ZERO
+                )
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert!(store.confirm(project, pr(number), recipient).unwrap());
+    }
+
+    #[test]
+    fn subscribe_notify_unsubscribe() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        subscribe(&store, "nixpkgs", 1, &a(), &["master".to_string()]);
+        subscribe(&store, "nixpkgs", 1, &b(), &[]);
+        subscribe(&store, "other", 1, &a(), &[]);
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [1]);
+
+        let subscriptions = store.subscriptions("nixpkgs", pr(1)).unwrap();
+        assert_eq!(
+            recipients(&subscriptions),
+            ["a@example.com", "b@example.com"]
+        );
+        assert!(subscriptions[0].notified.contains("master"));
+
+        store.queue(&b(), &notification(1, false)).unwrap();
+        let subscriptions = store.subscriptions("nixpkgs", pr(1)).unwrap();
+        assert_eq!(
+            subscriptions[1].notified,
+            HashSet::from(["staging".to_string()])
+        );
+
+        store.unsubscribe("nixpkgs", pr(1), &b()).unwrap();
+        let subscriptions = store.subscriptions("nixpkgs", pr(1)).unwrap();
+        assert_eq!(recipients(&subscriptions), ["a@example.com"]);
+
+        store.unsubscribe_all(&a()).unwrap();
+        assert!(store.prs("nixpkgs").unwrap().is_empty());
+        assert!(store.prs("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn confirmation() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        assert_eq!(
+            store
+                .subscribe("nixpkgs", pr(1), &a(), &[], &[], Duration::ZERO)
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert!(store.prs("nixpkgs").unwrap().is_empty());
+        assert!(store.subscriptions("nixpkgs", pr(1)).unwrap().is_empty());
+        assert!(!store.confirm("nixpkgs", pr(1), &b()).unwrap());
+
+        assert!(store.confirm("nixpkgs", pr(1), &a()).unwrap());
+        assert!(store.confirm("nixpkgs", pr(1), &a()).unwrap());
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [1]);
+        assert_eq!(
+            store
+                .subscribe("nixpkgs", pr(1), &a(), &[], &[], Duration::ZERO)
+                .unwrap(),
+            Subscribing::Subscribed
+        );
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [1]);
+
+        store
+            .subscribe("nixpkgs", pr(2), &a(), &[], &[], Duration::ZERO)
+            .unwrap();
+        store
+            .subscribe("nixpkgs", pr(2), &b(), &[], &[], Duration::ZERO)
+            .unwrap();
+        assert_eq!(store.prune_unconfirmed(Duration::from_secs(60)).unwrap(), 0);
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute("UPDATE subscriptions SET created_at = created_at - 120", [])
+            .unwrap();
+        assert_eq!(store.prune_unconfirmed(Duration::from_secs(60)).unwrap(), 2);
+        assert!(!store.confirm("nixpkgs", pr(2), &b()).unwrap());
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [1]);
+    }
+
+    #[test]
+    fn lowercasing_email_domains() {
+        let connection = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..2] {
+            connection.execute_batch(migration).unwrap();
+        }
+        connection.pragma_update(None, "user_version", 2).unwrap();
+        connection
+            .execute_batch(
+                "INSERT INTO subscribers (id, email, created_at) VALUES
+                     (1, 'A@Example.COM', 0),
+                     (2, 'A@example.com', 0),
+                     (3, 'b@Example.com', 0),
+                     (4, 'C@Example.COM', 0),
+                     (5, 'C@EXAMPLE.com', 0);
+                 INSERT INTO prs (id, project, number, created_at) VALUES
+                     (1, 'nixpkgs', 1, 0), (2, 'nixpkgs', 2, 0);
+                 INSERT INTO subscriptions (subscriber, pr, created_at, confirmed_at) VALUES
+                     (1, 1, 0, 0), (2, 2, 0, 0), (3, 1, 0, 0), (4, 1, 0, 0), (5, 2, 0, 0);",
+            )
+            .unwrap();
+
+        let store = Sqlite::new(connection, &[]).unwrap();
+        let addresses: Vec<String> = store
+            .connection
+            .lock()
+            .unwrap()
+            .prepare("SELECT address FROM subscribers ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            addresses,
+            ["A@example.com", "b@example.com", "C@example.com"]
+        );
+        // Variants that were both not lowercase are merged too.
+        for number in [1, 2] {
+            let subscriptions = store.subscriptions("nixpkgs", pr(number)).unwrap();
+            assert!(recipients(&subscriptions).contains(&"A@example.com"));
+            assert!(recipients(&subscriptions).contains(&"C@example.com"));
+        }
+    }
+
+    #[test]
+    fn resending_confirmations() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        let filter: Filter = "author:octocat".parse().unwrap();
+        let minute = Duration::from_secs(60);
+        let subscribe = || {
+            store
+                .subscribe("nixpkgs", pr(1), &a(), &[], &[], minute)
+                .unwrap()
+        };
+        let subscribe_standing = || {
+            store
+                .subscribe_standing("nixpkgs", &filter, &a(), minute)
+                .unwrap()
+        };
+
+        assert_eq!(subscribe(), Subscribing::Pending);
+        assert_eq!(subscribe(), Subscribing::StillPending);
+        assert_eq!(subscribe_standing(), Subscribing::Pending);
+        assert_eq!(subscribe_standing(), Subscribing::StillPending);
+
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "UPDATE subscriptions SET created_at = created_at - 120;
+                 UPDATE standing_subscriptions SET created_at = created_at - 120;",
+            )
+            .unwrap();
+        assert_eq!(subscribe(), Subscribing::Pending);
+        assert_eq!(subscribe_standing(), Subscribing::Pending);
+    }
+
+    #[test]
+    fn remove_pr() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        subscribe(&store, "nixpkgs", 1, &a(), &[]);
+        subscribe(&store, "nixpkgs", 2, &a(), &[]);
+        store.remove_pr("nixpkgs", pr(1)).unwrap();
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [2]);
+        assert!(store.subscriptions("nixpkgs", pr(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn targets() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        let targets = ["staging".to_string(), "master".to_string()];
+        assert_eq!(
+            store
+                .subscribe("nixpkgs", pr(1), &a(), &[], &targets, Duration::ZERO)
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert!(store.confirm("nixpkgs", pr(1), &a()).unwrap());
+        subscribe(&store, "nixpkgs", 1, &b(), &[]);
+
+        let subscriptions = store.subscriptions("nixpkgs", pr(1)).unwrap();
+        assert_eq!(subscriptions[0].targets, HashSet::from(targets));
+        assert!(subscriptions[1].targets.is_empty());
+
+        // Reaching the last target ends the subscription, but not the
+        // notification saying so.
+        store.queue(&a(), &notification(1, true)).unwrap();
+        let subscriptions = store.subscriptions("nixpkgs", pr(1)).unwrap();
+        assert_eq!(recipients(&subscriptions), ["b@example.com"]);
+        assert_eq!(store.due(10).unwrap()[0].recipient, a());
+    }
+
+    #[test]
+    fn managing() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        let filter: Filter = "label:security".parse().unwrap();
+        assert_eq!(store.delivery(&a()).unwrap(), None);
+
+        subscribe(&store, "nixpkgs", 2, &a(), &[]);
+        subscribe(&store, "nixpkgs", 1, &a(), &[]);
+        assert_eq!(
+            store
+                .subscribe("nixpkgs", pr(3), &a(), &[], &[], Duration::ZERO)
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert_eq!(
+            store
+                .subscribe_standing("nixpkgs", &filter, &a(), Duration::ZERO)
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert!(store.confirm_standing("nixpkgs", &filter, &a()).unwrap());
+        assert_eq!(store.delivery(&a()).unwrap(), Some(Delivery::Immediate));
+
+        // Unconfirmed subscriptions aren't shown.
+        let subscribed = store.subscribed(&a()).unwrap();
+        assert_eq!(
+            subscribed.iter().map(|s| s.pr.get()).collect::<Vec<_>>(),
+            [1, 2]
+        );
+
+        let targets = ["master".to_string()];
+        assert!(store.set_targets("nixpkgs", pr(1), &a(), &targets).unwrap());
+        assert!(!store.set_targets("nixpkgs", pr(1), &b(), &targets).unwrap());
+        assert_eq!(
+            store.subscribed(&a()).unwrap()[0].targets,
+            HashSet::from(targets)
+        );
+        assert!(store.set_targets("nixpkgs", pr(1), &a(), &[]).unwrap());
+        assert!(store.subscribed(&a()).unwrap()[0].targets.is_empty());
+
+        assert_eq!(
+            store.standing_of(&a()).unwrap(),
+            [("nixpkgs".to_string(), filter.clone())]
+        );
+        store
+            .unsubscribe_standing("nixpkgs", &filter, &a())
+            .unwrap();
+        assert!(store.standing_of(&a()).unwrap().is_empty());
+        assert_eq!(store.subscribed(&a()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn standing_subscriptions() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        let filter: Filter = "author:octocat".parse().unwrap();
+        let now = SystemTime::now();
+
+        assert_eq!(
+            store
+                .subscribe_standing("nixpkgs", &filter, &a(), Duration::ZERO)
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert!(store.standing("nixpkgs").unwrap().is_empty());
+        assert!(store.confirm_standing("nixpkgs", &filter, &a()).unwrap());
+        assert_eq!(
+            store
+                .subscribe_standing("nixpkgs", &filter, &a(), Duration::ZERO)
+                .unwrap(),
+            Subscribing::Subscribed
+        );
+
+        let standing = store.standing("nixpkgs").unwrap();
+        assert_eq!(standing.len(), 1);
+        assert_eq!(standing[0].filter, filter);
+        assert_eq!(standing[0].searched_at, None);
+        let id = standing[0].id;
+
+        subscribe(&store, "nixpkgs", 2, &a(), &[]);
+        assert_eq!(
+            numbers(store.expand(id, &[pr(1), pr(2)], now).unwrap()),
+            [1]
+        );
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [1, 2]);
+        assert!(store.standing("nixpkgs").unwrap()[0].searched_at.is_some());
+        let subscriptions = store.subscriptions("nixpkgs", pr(1)).unwrap();
+        assert_eq!(subscriptions[0].filter.as_ref(), Some(&filter));
+
+        // Unsubscribing from a PR that was found sticks.
+        store.unsubscribe("nixpkgs", pr(1), &a()).unwrap();
+        assert!(store.expand(id, &[pr(1)], now).unwrap().is_empty());
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [2]);
+
+        // The standing subscription keeps the subscriber.
+        store.unsubscribe("nixpkgs", pr(2), &a()).unwrap();
+        assert_eq!(store.standing("nixpkgs").unwrap().len(), 1);
+        store.unsubscribe_all(&a()).unwrap();
+        assert!(store.standing("nixpkgs").unwrap().is_empty());
+    }
+
+    #[test]
+    fn feeds() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        let branch = |name: &str| Branch {
+            name: name.to_string(),
+            hydra_link: None,
+        };
+        let names = |reached: Vec<Reached>| -> Vec<(i64, String)> {
+            reached
+                .into_iter()
+                .map(|r| (r.pr.get(), r.branch.name))
+                .collect()
+        };
+
+        assert!(!store.read("nixpkgs", pr(1)).unwrap());
+        assert!(store.watch("nixpkgs", pr(1), 2).unwrap());
+        assert!(store.read("nixpkgs", pr(1)).unwrap());
+        assert!(store.watch("nixpkgs", pr(2), 2).unwrap());
+        // Once there are too many, no more are watched, but the ones
+        // that already were still are.
+        assert!(!store.watch("nixpkgs", pr(3), 2).unwrap());
+        assert!(store.watch("nixpkgs", pr(1), 2).unwrap());
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [1, 2]);
+
+        let master = [branch("master")];
+        store
+            .record_reached("nixpkgs", pr(1), "one", &master, false)
+            .unwrap();
+        store
+            .record_reached("nixpkgs", pr(2), "two", &master, false)
+            .unwrap();
+        // Reaching a branch again doesn't make a new entry.
+        let both = [branch("master"), branch("nixos-unstable")];
+        store
+            .record_reached("nixpkgs", pr(1), "one", &both, true)
+            .unwrap();
+        assert_eq!(
+            names(store.reached("nixpkgs", &[pr(1), pr(2)], 10).unwrap()),
+            [
+                (1, "nixos-unstable".to_string()),
+                (2, "master".to_string()),
+                (1, "master".to_string()),
+            ]
+        );
+        assert_eq!(store.reached("nixpkgs", &[pr(2)], 10).unwrap().len(), 1);
+
+        // Finished PRs don't need updating.
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [2]);
+
+        // Unread feeds are forgotten, unless somebody's subscribed.
+        subscribe(&store, "nixpkgs", 2, &a(), &[]);
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(store.prune_unwatched(day).unwrap(), 0);
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE watched_prs SET read_at = read_at - 2 * 24 * 60 * 60",
+                [],
+            )
+            .unwrap();
+        assert_eq!(store.prune_unwatched(day).unwrap(), 2);
+        assert!(store.reached("nixpkgs", &[pr(1)], 10).unwrap().is_empty());
+        assert_eq!(store.reached("nixpkgs", &[pr(2)], 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn outbox() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        subscribe(&store, "nixpkgs", 1, &a(), &[]);
+        subscribe(&store, "nixpkgs", 2, &b(), &[]);
+        store.queue(&a(), &notification(1, true)).unwrap();
+        store.queue(&b(), &notification(2, false)).unwrap();
+
+        // The last notification outlives the PR it's about.
+        store.remove_pr("nixpkgs", pr(1)).unwrap();
+        let due = store.due(10).unwrap();
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].recipient, a());
+        assert_eq!(due[0].message, Message::Notification(notification(1, true)));
+
+        let retry_in = Duration::from_secs(60);
+        assert_eq!(store.failed(due[0].id, "busy", retry_in, false).unwrap(), 0);
+        assert_eq!(
+            store
+                .failed(due[1].id, "no such user", retry_in, true)
+                .unwrap(),
+            1
+        );
+        assert!(store.due(10).unwrap().is_empty());
+
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE outbox SET next_attempt_at = next_attempt_at - 120",
+                [],
+            )
+            .unwrap();
+        let due = store.due(10).unwrap();
+        assert_eq!(due[0].attempts, 1);
+        store.delivered(due[0].id).unwrap();
+        let remaining: i64 = store
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT count(*) FROM subscribers WHERE address = ?1",
+                [a()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(remaining, 0);
+
+        store.disable(&b()).unwrap();
+        assert!(store.due(10).unwrap().is_empty());
+        assert!(store.prs("nixpkgs").unwrap().is_empty());
+        assert_eq!(
+            store
+                .subscribe("nixpkgs", pr(2), &b(), &[], &[], Duration::ZERO)
+                .unwrap(),
+            Subscribing::Pending
+        );
+        assert!(store.confirm("nixpkgs", pr(2), &b()).unwrap());
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [2]);
+    }
+
+    #[test]
+    fn expiring() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        subscribe(&store, "nixpkgs", 1, &a(), &[]);
+        subscribe(&store, "nixpkgs", 2, &b(), &[]);
+        store.queue(&a(), &notification(1, false)).unwrap();
+        store.queue(&a(), &notification(1, true)).unwrap();
+        store.queue(&b(), &notification(2, false)).unwrap();
+
+        let due = store.due(10).unwrap();
+        let retry_in = Duration::ZERO;
+        store
+            .failed(due[0].id, "timed out", retry_in, false)
+            .unwrap();
+        store
+            .failed(due[1].id, "timed out", retry_in, false)
+            .unwrap();
+        let max_age = Duration::from_secs(60);
+        assert!(store.expire(max_age).unwrap().is_empty());
+
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute("UPDATE outbox SET created_at = created_at - 120", [])
+            .unwrap();
+        // Messages that haven't been tried yet are left alone, and
+        // however many have expired, it counts as one failure.
+        assert_eq!(store.expire(max_age).unwrap(), [(a(), 1)]);
+        let due = store.due(10).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].recipient, b());
+    }
+
+    #[test]
+    fn confirming_keeps_delivery() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        subscribe(&store, "nixpkgs", 1, &a(), &[]);
+        store.set_delivery(&a(), Delivery::Weekly).unwrap();
+        subscribe(&store, "nixpkgs", 2, &a(), &[]);
+        assert_eq!(store.delivery(&a()).unwrap(), Some(Delivery::Weekly));
+    }
+
+    #[test]
+    fn digests() {
+        let store = Sqlite::open_in_memory(&[]).unwrap();
+        subscribe(&store, "nixpkgs", 1, &a(), &[]);
+        subscribe(&store, "nixpkgs", 2, &a(), &[]);
+        store.set_delivery(&a(), Delivery::Daily).unwrap();
+
+        // Kept notifications are replaced by later ones about the same
+        // PR, which include their branches.
+        store.queue(&a(), &notification(2, false)).unwrap();
+        store.queue(&a(), &notification(1, false)).unwrap();
+        store.queue(&a(), &notification(1, true)).unwrap();
+        let subscriptions = store.subscriptions("nixpkgs", pr(2)).unwrap();
+        assert!(subscriptions[0].notified.is_empty());
+        assert_eq!(store.collect_digests().unwrap(), 0);
+        assert!(store.due(10).unwrap().is_empty());
+
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE digest_items SET created_at = created_at - 86400",
+                [],
+            )
+            .unwrap();
+        assert_eq!(store.collect_digests().unwrap(), 1);
+        assert_eq!(store.collect_digests().unwrap(), 0);
+        let due = store.due(10).unwrap();
+        assert_eq!(
+            due[0].message,
+            Message::Digest {
+                notifications: vec![notification(1, true), notification(2, false)]
+            }
+        );
+        let subscriptions = store.subscriptions("nixpkgs", pr(2)).unwrap();
+        assert!(subscriptions[0].notified.contains("staging"));
+    }
+
+    #[test]
+    fn import_data_folder() {
+        let folder = std::env::temp_dir().join(format!("pr-tracker-import-{}", std::process::id()));
+        let _ = remove_dir_all(&folder);
+        create_dir_all(folder.join("123")).unwrap();
+        create_dir_all(folder.join("home-manager")).unwrap();
+        write(folder.join("123/a@example.com"), r#"["master"]"#).unwrap();
+        write(folder.join("123/not an email"), "[]").unwrap();
+
+        let store = Sqlite::open_in_memory(&[("nixpkgs", folder.clone())]).unwrap();
+        remove_dir_all(&folder).unwrap();
+
+        assert_eq!(numbers(store.prs("nixpkgs").unwrap()), [123]);
+        let subscriptions = store.subscriptions("nixpkgs", pr(123)).unwrap();
+        assert_eq!(recipients(&subscriptions), ["a@example.com"]);
+        assert!(subscriptions[0].notified.contains("master"));
+    }
+}
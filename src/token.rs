@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::os::unix::ffi::OsStringExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::types::{Delivery, Filter, PrNumber, Recipient};
+
+/// The shortest key tokens can be signed with, in bytes.  Anyone who
+/// guesses the key can forge tokens, and webhook secrets are derived
+/// from it too.
+const MIN_SECRET_LEN: usize = 32;
+
+/// The key tokens are signed with, which must stay the same across
+/// restarts for links in emails we've already sent to keep working.
+pub static SECRET: Lazy<Vec<u8>> =
+    Lazy::new(
+        || match parse_secret(std::env::var_os("PR_TRACKER_SECRET")) {
+            Ok(secret) => secret,
+            Err(e) => {
+                eprintln!("pr-tracker: PR_TRACKER_SECRET: {}", e);
+                std::process::exit(78);
+            }
+        },
+    );
+
+#[derive(Debug, PartialEq)]
+pub enum SecretError {
+    Missing,
+    TooShort(usize),
+}
+
+impl Display for SecretError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use SecretError::*;
+        match self {
+            Missing => write!(f, "not set"),
+            TooShort(len) => write!(
+                f,
+                "{} bytes long, but must be at least {}",
+                len, MIN_SECRET_LEN
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+fn parse_secret(value: Option<OsString>) -> Result<Vec<u8>, SecretError> {
+    let secret = value.ok_or(SecretError::Missing)?.into_vec();
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(SecretError::TooShort(secret.len()));
+    }
+    Ok(secret)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Malformed => write!(f, "malformed token"),
+            BadSignature => write!(f, "bad token signature"),
+            Expired => write!(f, "expired token"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What a token allows whoever has it to do.  Recipients are called
+/// `email` in tokens, because that's all they could be when the first
+/// links were sent.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "use", rename_all = "snake_case")]
+pub enum Claims {
+    /// Confirm a pending subscription, and set how the subscriber
+    /// wants to hear about their PRs, if they said.
+    Confirm {
+        repo: String,
+        pr: PrNumber,
+        #[serde(rename = "email")]
+        recipient: Recipient,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delivery: Option<Delivery>,
+    },
+    /// Confirm a pending standing subscription, like `Confirm`.
+    ConfirmStanding {
+        repo: String,
+        filter: Filter,
+        #[serde(rename = "email")]
+        recipient: Recipient,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delivery: Option<Delivery>,
+    },
+    /// See and change all of a recipient's subscriptions.
+    Manage {
+        #[serde(rename = "email")]
+        recipient: Recipient,
+    },
+    /// Unsubscribe from a PR, or a standing subscription, or from
+    /// everything if neither is given.
+    Unsubscribe {
+        #[serde(rename = "email")]
+        recipient: Recipient,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        repo: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pr: Option<PrNumber>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<Filter>,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+struct Payload {
+    #[serde(flatten)]
+    claims: Claims,
+    /// Seconds since the epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+/// Returns an HMAC-SHA256 keyed with `key`.
+pub fn mac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::new_from_slice(key).expect("HMAC takes keys of any length")
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn sign_with(key: &[u8], claims: Claims, expires: Option<SystemTime>) -> String {
+    let payload = Payload {
+        claims,
+        expires: expires.map(seconds),
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&payload).unwrap());
+
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+    format!("{}.{}", payload, signature)
+}
+
+fn verify_with(key: &[u8], token: &str, now: SystemTime) -> Result<Claims, Error> {
+    let (payload, signature) = token.split_once('.').ok_or(Error::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| Error::Malformed)?;
+
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| Error::BadSignature)?;
+
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| Error::Malformed)?;
+    let payload: Payload = serde_json::from_slice(&payload).map_err(|_| Error::Malformed)?;
+    if payload
+        .expires
+        .is_some_and(|expires| expires <= seconds(now))
+    {
+        return Err(Error::Expired);
+    }
+
+    Ok(payload.claims)
+}
+
+/// Returns a token granting `claims`, which stops working after
+/// `valid_for`, if given.
+pub fn sign(claims: Claims, valid_for: Option<Duration>) -> String {
+    let expires = valid_for.map(|valid_for| SystemTime::now() + valid_for);
+    sign_with(&SECRET, claims, expires)
+}
+
+/// Checks that `token` was made by [`sign`] and hasn't expired, and
+/// returns what it grants.
+pub fn verify(token: &str) -> Result<Claims, Error> {
+    verify_with(&SECRET, token, SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> Claims {
+        Claims::Confirm {
+            repo: "nixpkgs".to_string(),
+            pr: "123".parse().unwrap(),
+            recipient: "a@example.com".parse().unwrap(),
+            delivery: Some(Delivery::Daily),
+        }
+    }
+
+    #[test]
+    fn secret_length() {
+        assert_eq!(parse_secret(None), Err(SecretError::Missing));
+        assert_eq!(parse_secret(Some("".into())), Err(SecretError::TooShort(0)));
+        assert_eq!(
+            parse_secret(Some("x".repeat(31).into())),
+            Err(SecretError::TooShort(31))
+        );
+        assert_eq!(
+            parse_secret(Some("x".repeat(32).into())),
+            Ok(vec![b'x'; 32])
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let token = sign_with(b"key", claims(), None);
+        assert_eq!(verify_with(b"key", &token, SystemTime::now()), Ok(claims()));
+    }
+
+    #[test]
+    fn wrong_key() {
+        let token = sign_with(b"key", claims(), None);
+        let result = verify_with(b"other key", &token, SystemTime::now());
+        assert_eq!(result, Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn tampered() {
+        let token = sign_with(b"key", claims(), None);
+        let (_, signature) = token.split_once('.').unwrap();
+        let payload = URL_SAFE_NO_PAD
+            .encode(r#"{"use":"confirm","repo":"nixpkgs","pr":123,"email":"b@example.com"}"#);
+        let forged = format!("{}.{}", payload, signature);
+        let result = verify_with(b"key", &forged, SystemTime::now());
+        assert_eq!(result, Err(Error::BadSignature));
+        assert_eq!(
+            verify_with(b"key", "nonsense", SystemTime::now()),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn uses() {
+        let token = sign_with(b"key", claims(), None);
+        let (payload, _) = token.split_once('.').unwrap();
+        let payload = URL_SAFE_NO_PAD.decode(payload).unwrap();
+        assert!(String::from_utf8(payload)
+            .unwrap()
+            .contains(r#""use":"confirm""#));
+
+        let unsubscribe = Claims::Unsubscribe {
+            recipient: "a@example.com".parse().unwrap(),
+            repo: None,
+            pr: None,
+            filter: None,
+        };
+        let token = sign_with(b"key", unsubscribe, None);
+        assert!(matches!(
+            verify_with(b"key", &token, SystemTime::now()),
+            Ok(Claims::Unsubscribe { pr: None, .. })
+        ));
+    }
+
+    #[test]
+    fn hostile_claims() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"use":"unsubscribe","email":"../../x"}"#);
+        let mut mac = mac(b"key");
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        let token = format!("{}.{}", payload, signature);
+        let result = verify_with(b"key", &token, SystemTime::now());
+        assert_eq!(result, Err(Error::Malformed));
+    }
+
+    #[test]
+    fn expiry() {
+        let now = SystemTime::now();
+        let token = sign_with(b"key", claims(), Some(now + Duration::from_secs(60)));
+        assert_eq!(verify_with(b"key", &token, now), Ok(claims()));
+        let later = now + Duration::from_secs(61);
+        assert_eq!(verify_with(b"key", &token, later), Err(Error::Expired));
+    }
+}
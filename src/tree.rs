@@ -2,41 +2,122 @@
 // SPDX-FileCopyrightText: 2021 Alyssa Ross <hi@alyssa.is>
 // SPDX-FileCopyrightText: 2022 Arnout Engelen <arnout@bzzt.net>
 
-use std::collections::BTreeSet;
-use std::ffi::{OsStr, OsString};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Formatter};
 
 use askama::Template;
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::branches::branch_hydra_link;
-use crate::branches::next_branches;
+use crate::branches::{self, BranchRules};
 use crate::github;
-use crate::nixpkgs::Nixpkgs;
+use crate::nixpkgs::{Branches, Nixpkgs};
 
-#[derive(Debug, Template)]
-#[template(path = "tree.html")]
+#[derive(Debug, Serialize)]
 pub struct Tree {
+    #[serde(rename = "branch")]
     branch_name: String,
+    #[serde(rename = "state", serialize_with = "serialize_accepted")]
     accepted: Option<bool>,
     hydra_link: Option<String>,
     children: Vec<Tree>,
 }
 
+/// Why a subscription can't wait for a PR to reach some branches.
+#[derive(Debug, PartialEq)]
+pub enum TargetsError {
+    /// The branches that aren't in the tree.
+    Unknown(Vec<String>),
+    /// The PR has already reached all of them.
+    Reached,
+}
+
+impl Display for TargetsError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(branches) => write!(f, "This PR won't reach {}.", branches.join(", ")),
+            Self::Reached => write!(f, "This PR has already reached all of those branches."),
+        }
+    }
+}
+
+impl std::error::Error for TargetsError {}
+
+/// A tree as it's shown on a page, with a box to tick for each branch
+/// the PR is still to reach.  The boxes belong to the form with the ID
+/// `form`, and the ones for branches in `ticked` start off ticked.
+/// Trees whose branches can't be ticked have an empty `form`.
+#[derive(Template)]
+#[template(path = "tree.html")]
+pub struct TreeView<'a> {
+    tree: &'a Tree,
+    form: &'a str,
+    ticked: &'a HashSet<String>,
+}
+
+impl TreeView<'_> {
+    fn is_ticked(&self) -> bool {
+        self.ticked.contains(&self.tree.branch_name)
+    }
+}
+
+/// A branch in a tree, for listing outside of the tree itself.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Branch {
+    pub name: String,
+    pub hydra_link: Option<String>,
+}
+
+fn serialize_accepted<S: Serializer>(accepted: &Option<bool>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(match accepted {
+        Some(true) => "accepted",
+        Some(false) => "pending",
+        None => "unknown",
+    })
+}
+
 impl Tree {
-    fn generate(branch: String, found_branches: &mut BTreeSet<OsString>) -> Tree {
-        found_branches.insert((&branch).into());
+    pub fn view<'a>(&'a self, form: &'a str, ticked: &'a HashSet<String>) -> TreeView<'a> {
+        TreeView {
+            tree: self,
+            form,
+            ticked,
+        }
+    }
+
+    fn generate(branch: String, rules: &BranchRules) -> Tree {
+        Self::generate_from(branch, rules, &mut Vec::new())
+    }
+
+    /// Generates the tree below `branch`, leaving out branches that
+    /// `ancestors` already go through, so that rules that are merged
+    /// back into themselves can't make the tree go on forever.
+    fn generate_from(branch: String, rules: &BranchRules, ancestors: &mut Vec<String>) -> Tree {
+        let nexts: Vec<_> = if ancestors.len() + 1 < branches::MAX_DEPTH {
+            rules
+                .next_branches(&branch)
+                .into_iter()
+                .map(|b| b.into_owned())
+                .filter(|b| *b != branch && !ancestors.contains(b))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let link = rules.branch_hydra_link(&branch);
 
-        let nexts = next_branches(&branch)
+        ancestors.push(branch);
+        let children = nexts
             .into_iter()
-            .map(|b| Self::generate(b.to_string(), found_branches))
+            .map(|b| Self::generate_from(b, rules, ancestors))
             .collect();
-
-        let link = branch_hydra_link(&branch);
+        let branch = ancestors.pop().unwrap();
 
         Tree {
             accepted: None,
             branch_name: branch,
             hydra_link: link,
-            children: nexts,
+            children,
         }
     }
     pub fn collect_branches(&self, vec: &mut Vec<String>) -> bool {
@@ -52,62 +133,518 @@ impl Tree {
         res
     }
 
-    fn fill_accepted(&mut self, branches: &BTreeSet<OsString>, missing_means_absent: bool) {
-        self.accepted = match branches.contains(OsStr::new(&self.branch_name)) {
-            true => Some(true),
-            false if missing_means_absent => Some(false),
-            false => None,
+    /// Returns the name of every branch in the tree.
+    pub fn names(&self) -> HashSet<String> {
+        let mut edges = Vec::new();
+        self.edges(None, &mut edges);
+        edges
+            .into_iter()
+            .map(|(branch, _)| branch.to_string())
+            .collect()
+    }
+
+    /// Checks that `targets` are branches in the tree, and that the PR
+    /// is still to reach at least one of them, unless there are none.
+    pub fn check_targets(&self, targets: &[String]) -> Result<(), TargetsError> {
+        let names = self.names();
+        let unknown: Vec<_> = targets
+            .iter()
+            .filter(|target| !names.contains(*target))
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            return Err(TargetsError::Unknown(unknown));
+        }
+
+        let mut reached = Vec::new();
+        self.collect_branches(&mut reached);
+        if !targets.is_empty() && targets.iter().all(|target| reached.contains(target)) {
+            return Err(TargetsError::Reached);
+        }
+        Ok(())
+    }
+
+    /// Lists the branches in the tree that are in `names`, each once,
+    /// parents first.
+    pub fn branches(&self, names: &HashSet<String>) -> Vec<Branch> {
+        let mut seen = HashSet::new();
+        let mut branches = Vec::new();
+        self.find_branches(names, &mut seen, &mut branches);
+        branches
+    }
+
+    fn find_branches<'a>(
+        &'a self,
+        names: &HashSet<String>,
+        seen: &mut HashSet<&'a str>,
+        out: &mut Vec<Branch>,
+    ) {
+        if names.contains(&self.branch_name) && seen.insert(&self.branch_name) {
+            out.push(Branch {
+                name: self.branch_name.clone(),
+                hydra_link: self.hydra_link.clone(),
+            });
+        }
+        for child in &self.children {
+            child.find_branches(names, seen, out);
+        }
+    }
+
+    /// Lists each branch in the tree along with its parent, parents
+    /// first.
+    fn edges<'a>(&'a self, parent: Option<&'a str>, out: &mut Vec<(&'a str, Option<&'a str>)>) {
+        out.push((&self.branch_name, parent));
+        for child in &self.children {
+            child.edges(Some(&self.branch_name), out);
+        }
+    }
+
+    /// Combines the trees of PRs that make the same change, like a PR
+    /// and its backports, so that a branch has been reached if any of
+    /// them reached it.  A tree whose base branch is in an earlier one
+    /// is joined onto it there, and the rest are kept, in order.
+    pub fn union(trees: impl IntoIterator<Item = Tree>) -> Vec<Tree> {
+        let mut accepted = BTreeMap::new();
+        let mut union: Vec<Tree> = Vec::new();
+        for tree in trees {
+            tree.add_accepted(&mut accepted);
+            match union.iter_mut().find_map(|t| t.find_mut(&tree.branch_name)) {
+                Some(branch) => branch.join(tree),
+                None => union.push(tree),
+            }
+        }
+        for tree in &mut union {
+            tree.fill_accepted(&accepted);
+        }
+        union
+    }
+
+    fn find_mut(&mut self, name: &str) -> Option<&mut Tree> {
+        if self.branch_name == name {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_mut(name))
+    }
+
+    /// Adds the branches of `other` missing from this tree.
+    fn join(&mut self, other: Tree) {
+        for child in other.children {
+            match self
+                .children
+                .iter_mut()
+                .find(|c| c.branch_name == child.branch_name)
+            {
+                Some(c) => c.join(child),
+                None => self.children.push(child),
+            }
+        }
+    }
+
+    /// Adds whether each branch in the tree has been reached to
+    /// `accepted`, where reaching it in either counts.
+    fn add_accepted(&self, accepted: &mut BTreeMap<String, Option<bool>>) {
+        let state = accepted
+            .entry(self.branch_name.clone())
+            .or_insert(self.accepted);
+        *state = match (*state, self.accepted) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
         };
+        for child in &self.children {
+            child.add_accepted(accepted);
+        }
+    }
+
+    fn fill_accepted(&mut self, accepted: &BTreeMap<String, Option<bool>>) {
+        self.accepted = accepted.get(&self.branch_name).copied().flatten();
 
         for child in self.children.iter_mut() {
-            child.fill_accepted(branches, missing_means_absent);
+            child.fill_accepted(accepted);
         }
     }
 
+    /// Works out which branches in the tree contain `merge_commit`,
+    /// asking git only about branches that might.
+    async fn find_accepted(
+        &self,
+        merge_commit: &str,
+        repo: &impl Branches,
+    ) -> BTreeMap<String, Option<bool>> {
+        let mut edges = Vec::new();
+        self.edges(None, &mut edges);
+
+        // A branch can be merged into from more than one branch.
+        let mut parents: Vec<(&str, Vec<&str>)> = Vec::new();
+        for (branch, parent) in edges {
+            match parents.iter_mut().find(|(b, _)| *b == branch) {
+                Some((_, ps)) => ps.extend(parent),
+                None => parents.push((branch, parent.into_iter().collect())),
+            }
+        }
+
+        let mut accepted: BTreeMap<String, Option<bool>> = BTreeMap::new();
+        let mut failed = false;
+
+        while accepted.len() < parents.len() {
+            let undecided = || {
+                parents
+                    .iter()
+                    .filter(|(branch, _)| !accepted.contains_key(*branch))
+            };
+            // Branches are decided once all the branches merged into them
+            // are, unless branches are merged into each other, in which
+            // case they're decided as soon as any of them are.
+            let mut ready: Vec<_> = undecided()
+                .filter(|(_, ps)| ps.iter().all(|p| accepted.contains_key(*p)))
+                .collect();
+            if ready.is_empty() {
+                ready = undecided()
+                    .filter(|(_, ps)| ps.iter().any(|p| accepted.contains_key(*p)))
+                    .collect();
+            }
+
+            let mut states = Vec::new();
+            let mut check = Vec::new();
+            for (branch, ps) in ready {
+                let parent_states: Vec<_> = ps.iter().map(|p| accepted.get(*p).copied()).collect();
+                if ps.is_empty() {
+                    // Even if something goes wrong with our local Git
+                    // repo, we know that the base branch of the PR must
+                    // contain the commit, because GitHub told us it was
+                    // merged into it.
+                    states.push((*branch, Some(true)));
+                } else if parent_states
+                    .iter()
+                    .all(|state| *state == Some(Some(false)))
+                {
+                    // A commit can only reach a branch through the
+                    // branches before it, so there's no need to ask git.
+                    states.push((*branch, Some(false)));
+                } else if failed {
+                    states.push((*branch, None));
+                } else {
+                    check.push(*branch);
+                }
+            }
+
+            if !check.is_empty() {
+                let mut containing = BTreeSet::new();
+                match repo
+                    .branches_containing_commit(merge_commit, &check, &mut containing)
+                    .await
+                {
+                    Ok(()) => states.extend(
+                        check
+                            .iter()
+                            .map(|branch| (*branch, Some(containing.contains(OsStr::new(branch))))),
+                    ),
+                    Err(e) => {
+                        eprintln!("pr-tracker: branches_containing_commit: {}", e);
+                        failed = true;
+                        states.extend(check.iter().map(|branch| (*branch, None)));
+                    }
+                }
+            }
+
+            for (branch, state) in states {
+                accepted.insert(branch.to_string(), state);
+            }
+        }
+
+        accepted
+    }
+
     pub async fn make(
         base_branch: String,
         merge_status: &github::PullRequestStatus,
         nixpkgs: &Nixpkgs<'_>,
+        rules: &BranchRules,
     ) -> Tree {
-        let mut missing_means_absent = true;
-        let mut branches = BTreeSet::new();
+        let mut tree = Self::generate(base_branch.clone(), rules);
 
-        let mut tree = Self::generate(base_branch.clone(), &mut branches);
+        let accepted = match merge_status {
+            github::PullRequestStatus::Merged {
+                merge_commit_oid: Some(merge_commit),
+            } => tree.find_accepted(merge_commit, nixpkgs).await,
 
-        if let github::PullRequestStatus::Merged {
-            merge_commit_oid, ..
-        } = merge_status
-        {
-            if let Some(merge_commit) = merge_commit_oid {
-                let mut containing_commits = BTreeSet::new();
+            // GitHub didn't tell us the merge commit, so all we know
+            // is that the base branch contains it.
+            github::PullRequestStatus::Merged {
+                merge_commit_oid: None,
+            } => [(base_branch, Some(true))].into(),
 
-                if let Err(e) = nixpkgs
-                    .branches_containing_commit(merge_commit, &mut containing_commits)
-                    .await
-                {
-                    eprintln!("pr-tracker: branches_containing_commit: {}", e);
-                    missing_means_absent = false;
-                }
+            _ => {
+                let mut edges = Vec::new();
+                tree.edges(None, &mut edges);
+                edges
+                    .into_iter()
+                    .map(|(branch, _)| (branch.to_string(), Some(false)))
+                    .collect()
+            }
+        };
 
-                branches = branches
-                    .intersection(&containing_commits)
-                    .cloned()
-                    .collect();
-            } else {
-                branches.clear();
-                missing_means_absent = false;
+        tree.fill_accepted(&accepted);
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsString;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::sync::Mutex;
+
+    use crate::nixpkgs;
+
+    #[test]
+    fn branches_in_tree_order() {
+        let tree = Tree::generate("staging".to_string(), &BranchRules::default());
+        let names = ["master", "staging", "staging-next", "nonexistent"]
+            .map(String::from)
+            .into();
+        let branches = tree.branches(&names);
+
+        let names: Vec<_> = branches.iter().map(|branch| branch.name.as_str()).collect();
+        assert_eq!(names, ["staging", "staging-next", "master"]);
+        let expected = "https://hydra.nixos.org/jobset/nixpkgs/staging-next#tabs-jobs";
+        assert_eq!(branches[1].hydra_link.as_deref(), Some(expected));
+    }
+
+    #[test]
+    fn cycles() {
+        let rules = BranchRules::parse(
+            r#"
+[[next]]
+pattern = '\Aa-(.*)\z'
+next = ["b-$1"]
+
+[[next]]
+pattern = '\Ab-(.*)\z'
+next = ["a-$1", "c-$1"]
+
+[[next]]
+pattern = '\A(x+)\z'
+next = ["${1}x"]
+"#,
+        )
+        .unwrap();
+
+        let tree = Tree::generate("a-1".to_string(), &rules);
+        let mut edges = Vec::new();
+        tree.edges(None, &mut edges);
+        assert_eq!(
+            edges,
+            [("a-1", None), ("b-1", Some("a-1")), ("c-1", Some("b-1"))]
+        );
+
+        let tree = Tree::generate("x".to_string(), &rules);
+        assert_eq!(tree.names().len(), branches::MAX_DEPTH);
+    }
+
+    /// A repository where the branches in `containing` contain every
+    /// commit, and the ones in `failing` can't be looked at.
+    #[derive(Default)]
+    struct StubBranches {
+        containing: HashSet<&'static str>,
+        failing: HashSet<&'static str>,
+        asked: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Branches for StubBranches {
+        async fn branches_containing_commit(
+            &self,
+            _commit: &str,
+            branches: &[&str],
+            out: &mut BTreeSet<OsString>,
+        ) -> Result<(), nixpkgs::Error> {
+            let asked = branches.iter().map(|b| b.to_string()).collect();
+            self.asked.lock().unwrap().push(asked);
+            if branches.iter().any(|branch| self.failing.contains(branch)) {
+                let status = ExitStatus::from_raw(128 << 8);
+                return Err(nixpkgs::Error::ExitFailure(status));
             }
+            out.extend(
+                branches
+                    .iter()
+                    .filter(|branch| self.containing.contains(*branch))
+                    .map(OsString::from),
+            );
+            Ok(())
+        }
+    }
 
-            // Even if something goes wrong with our local Git repo,
-            // or GitHub didn't tell us the merge commit, we know that
-            // the base branch of the PR must contain the commit,
-            // because GitHub told us it was merged into it.
-            branches.insert(base_branch.into());
-        } else {
-            branches.clear();
+    fn asked(repo: &StubBranches) -> Vec<Vec<String>> {
+        std::mem::take(&mut *repo.asked.lock().unwrap())
+    }
+
+    #[async_std::test]
+    async fn accepted() {
+        let rules = BranchRules::parse(
+            r#"
+[[next]]
+pattern = '\Astaging\z'
+next = ["staging-next", "python-updates"]
+
+[[next]]
+pattern = '\Astaging-next\z'
+next = ["master"]
+
+[[next]]
+pattern = '\Apython-updates\z'
+next = ["master"]
+
+[[next]]
+pattern = '\Amaster\z'
+next = ["unstable"]
+"#,
+        )
+        .unwrap();
+        let tree = Tree::generate("staging".to_string(), &rules);
+        let state = |accepted: &BTreeMap<String, Option<bool>>, branch: &str| accepted[branch];
+
+        // Branches after ones that don't contain the commit aren't
+        // looked at, but master is, because it can be reached from
+        // python-updates even though staging-next hasn't got there.
+        let repo = StubBranches {
+            containing: ["python-updates", "master"].into(),
+            ..Default::default()
+        };
+        let accepted = tree.find_accepted("abc", &repo).await;
+        assert_eq!(state(&accepted, "staging"), Some(true));
+        assert_eq!(state(&accepted, "staging-next"), Some(false));
+        assert_eq!(state(&accepted, "python-updates"), Some(true));
+        assert_eq!(state(&accepted, "master"), Some(true));
+        assert_eq!(state(&accepted, "unstable"), Some(false));
+        assert_eq!(
+            asked(&repo),
+            [
+                vec!["staging-next", "python-updates"],
+                vec!["master"],
+                vec!["unstable"]
+            ]
+        );
+
+        let repo = StubBranches::default();
+        let accepted = tree.find_accepted("abc", &repo).await;
+        assert_eq!(state(&accepted, "master"), Some(false));
+        assert_eq!(asked(&repo), [vec!["staging-next", "python-updates"]]);
+
+        // Once git fails, everything it wasn't asked about is unknown.
+        let repo = StubBranches {
+            containing: ["staging-next"].into(),
+            failing: ["python-updates"].into(),
+            ..Default::default()
+        };
+        let accepted = tree.find_accepted("abc", &repo).await;
+        assert_eq!(state(&accepted, "staging"), Some(true));
+        assert_eq!(state(&accepted, "staging-next"), None);
+        assert_eq!(state(&accepted, "master"), None);
+        assert_eq!(state(&accepted, "unstable"), None);
+        assert_eq!(asked(&repo).len(), 1);
+    }
+
+    #[test]
+    fn union() {
+        let rules = BranchRules::default();
+        let reached = |branch: &str, reached: &[&str]| {
+            let mut tree = Tree::generate(branch.to_string(), &rules);
+            let accepted = tree
+                .names()
+                .into_iter()
+                .map(|branch| {
+                    let state = reached.contains(&&*branch);
+                    (branch, Some(state))
+                })
+                .collect();
+            tree.fill_accepted(&accepted);
+            tree
+        };
+
+        let trees = Tree::union([
+            reached("staging", &["staging"]),
+            reached("master", &["master"]),
+            reached("release-23.11", &["release-23.11"]),
+        ]);
+        assert_eq!(trees.len(), 2);
+        assert_eq!(
+            trees[0].names(),
+            Tree::generate("staging".to_string(), &rules).names()
+        );
+
+        let mut accepted = BTreeMap::new();
+        for tree in &trees {
+            tree.add_accepted(&mut accepted);
         }
+        let state = |branch: &str| accepted.get(branch).copied().flatten();
+        assert_eq!(state("staging"), Some(true));
+        assert_eq!(state("staging-next"), Some(false));
+        assert_eq!(state("master"), Some(true));
+        assert_eq!(state("nixos-unstable-small"), Some(false));
+        assert_eq!(state("release-23.11"), Some(true));
+    }
 
-        tree.fill_accepted(&branches, missing_means_absent);
-        tree
+    #[test]
+    fn json() {
+        let rules = BranchRules::parse(
+            r#"
+[[next]]
+pattern = '\Astaging\z'
+next = ["master"]
+
+[[hydra]]
+pattern = '\Amaster\z'
+link = "https://hydra.example.com/master"
+"#,
+        )
+        .unwrap();
+        let mut tree = Tree::generate("staging".to_string(), &rules);
+        tree.fill_accepted(&[("staging".to_string(), Some(true))].into());
+
+        assert_eq!(
+            serde_json::to_value(&tree).unwrap(),
+            serde_json::json!({
+                "branch": "staging",
+                "state": "accepted",
+                "hydra_link": null,
+                "children": [{
+                    "branch": "master",
+                    "state": "unknown",
+                    "hydra_link": "https://hydra.example.com/master",
+                    "children": [],
+                }],
+            })
+        );
+
+        tree.fill_accepted(&[("staging".to_string(), Some(false))].into());
+        assert_eq!(serde_json::to_value(&tree).unwrap()["state"], "pending");
+    }
+
+    #[test]
+    fn targets() {
+        let mut tree = Tree::generate("staging".to_string(), &BranchRules::default());
+        tree.accepted = Some(true);
+        let targets = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tree.check_targets(&[]), Ok(()));
+        assert_eq!(tree.check_targets(&targets(&["staging", "master"])), Ok(()));
+        assert_eq!(
+            tree.check_targets(&targets(&["staging"])),
+            Err(TargetsError::Reached)
+        );
+        assert_eq!(
+            tree.check_targets(&targets(&["master", "nonexistent"])),
+            Err(TargetsError::Unknown(targets(&["nonexistent"])))
+        );
     }
 }
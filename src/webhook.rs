@@ -0,0 +1,461 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Posts notifications as JSON to URLs that subscribers give us, for
+//! chat and CI systems to pick up.
+//!
+//! Every request has an `X-PR-Tracker-Signature` header, holding
+//! `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with
+//! the webhook's secret.  The secret is included in the request that
+//! asks for the subscription to be confirmed, and stays the same for as
+//! long as `PR_TRACKER_SECRET` does.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_std::future;
+use async_std::net::{TcpStream, ToSocketAddrs};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::Mac;
+use serde::Serialize;
+use surf::http::headers::HeaderValue;
+use surf::http::{Request, Response};
+use surf::{StatusCode, Url};
+
+use crate::notifier::{self, unsubscribe_link, unsubscribe_standing_link, Notifier, Target};
+use crate::project::Project;
+use crate::store::Notification;
+use crate::token;
+use crate::tree::Branch;
+use crate::types::{is_public_address, Filter, PrNumber, Recipient, WebhookUrl};
+use crate::CONFIG;
+
+/// How long to wait for a webhook to respond before trying again
+/// later.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    Resolve(io::Error),
+    /// The webhook's host resolved to an address that isn't on the
+    /// internet.
+    Address(IpAddr),
+    Request(surf::Error),
+    Response(StatusCode),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Resolve(e) => write!(f, "resolving host: {}", e),
+            Address(ip) => write!(f, "{} is not a public address", ip),
+            Request(e) => write!(f, "request: {}", e),
+            Response(status) => write!(f, "response status {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the webhook is gone, or somewhere we won't post to, so
+    /// that trying again won't help.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Self::Address(_) | Self::Response(StatusCode::NotFound | StatusCode::Gone)
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Payload<'a> {
+    /// The webhook has been subscribed to a PR, or to every PR that
+    /// matches `filter`, which has to be confirmed by visiting
+    /// `confirm_url`.
+    Confirm {
+        repo: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pr: Option<PrNumber>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pr_title: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pr_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filter: Option<&'a Filter>,
+        confirm_url: &'a str,
+        /// What requests to the webhook will be signed with.
+        secret: String,
+    },
+    /// A PR has reached new branches.
+    BranchesReached {
+        repo: &'a str,
+        pr: PrNumber,
+        pr_title: &'a str,
+        pr_url: String,
+        /// The newly reached branches, parents first.
+        branches: &'a [Branch],
+        /// Whether the webhook won't hear about the PR again, because it
+        /// won't reach any more branches, or has reached all the ones
+        /// the subscription was waiting for.
+        last: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        unsubscribe_url: Option<String>,
+        /// The standing subscription that found the PR, if one did.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filter: Option<&'a Filter>,
+        /// Ends the standing subscription in `filter`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        unsubscribe_filter_url: Option<String>,
+    },
+    /// Somebody asked to manage the webhook's subscriptions, which they
+    /// can do by visiting `manage_url`.
+    Manage { manage_url: &'a str },
+    /// Several PRs have reached new branches since the last digest, with
+    /// a `branches_reached` event for each.
+    Digest { notifications: Vec<Payload<'a>> },
+}
+
+impl<'a> Payload<'a> {
+    fn branches_reached(
+        project: &'a Project,
+        to: &WebhookUrl,
+        notification: &'a Notification,
+    ) -> Self {
+        let pr = notification.pr;
+        let recipient = Recipient::Webhook(to.clone());
+        Self::BranchesReached {
+            repo: &project.name,
+            pr,
+            pr_title: &notification.pr_title,
+            pr_url: project.pull_link(pr),
+            branches: &notification.branches,
+            last: notification.last,
+            unsubscribe_url: (!notification.last)
+                .then(|| unsubscribe_link(&recipient, Some((project, pr)))),
+            filter: notification.filter.as_ref(),
+            unsubscribe_filter_url: notification
+                .filter
+                .as_ref()
+                .map(|filter| unsubscribe_standing_link(&recipient, project, filter)),
+        }
+    }
+}
+
+/// How webhooks' hosts are looked up and connected to, so that tests
+/// can stand in for the internet.
+trait Network {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream>;
+}
+
+struct Internet;
+
+impl Network for Internet {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs().await?.collect())
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr).await
+    }
+}
+
+/// The secret that requests to `url` are signed with.  It's derived
+/// from `PR_TRACKER_SECRET`, so that it doesn't need storing.
+fn secret(url: &WebhookUrl) -> String {
+    let mut mac = token::mac(&token::SECRET);
+    mac.update(b"webhook ");
+    mac.update(url.as_str().as_bytes());
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = token::mac(secret.as_bytes());
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// Looks up `host`, and checks that it only resolves to public
+/// addresses.  Addresses in webhook URLs are checked when they're given
+/// to us, but a name can point anywhere, and can change what it points
+/// to, so the addresses returned here are the only ones that may be
+/// connected to.
+async fn resolve(network: &impl Network, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+    let addrs = network.resolve(host, port).await.map_err(Error::Resolve)?;
+    if addrs.is_empty() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+        return Err(Error::Resolve(e));
+    }
+    for addr in &addrs {
+        if !is_public_address(addr.ip()) {
+            return Err(Error::Address(addr.ip()));
+        }
+    }
+    Ok(addrs)
+}
+
+/// Sends `request` to the first of `addrs` that can be connected to,
+/// speaking TLS to `host` if the URL asks for it.  The host is still
+/// what the certificate is checked against, and what's in the Host
+/// header.
+async fn send(
+    network: &impl Network,
+    addrs: &[SocketAddr],
+    host: &str,
+    request: Request,
+) -> surf::Result<Response> {
+    let mut last_error = None;
+    for addr in addrs {
+        let stream = match network.connect(*addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                last_error = Some(e);
+                continue;
+            }
+        };
+        return if request.url().scheme() == "https" {
+            let stream = async_native_tls::connect(host, stream).await?;
+            async_h1::connect(stream, request).await
+        } else {
+            async_h1::connect(stream, request).await
+        };
+    }
+    // resolve never returns an empty list.
+    Err(last_error.unwrap().into())
+}
+
+async fn post(url: &WebhookUrl, payload: &Payload<'_>) -> Result<(), Error> {
+    let body = serde_json::to_vec(payload).unwrap();
+    let user_agent = HeaderValue::from_bytes(CONFIG.user_agent.as_encoded_bytes().to_vec())
+        .map_err(Error::Request)?;
+
+    // Webhook URLs are always valid.
+    let mut request = Request::post(Url::parse(url.as_str()).unwrap());
+    request.insert_header("User-Agent", user_agent);
+    request.insert_header("X-PR-Tracker-Signature", signature(&secret(url), &body));
+    request.set_content_type(surf::http::mime::JSON);
+    request.set_body(body);
+    deliver(&Internet, request).await
+}
+
+async fn deliver(network: &impl Network, request: Request) -> Result<(), Error> {
+    // Webhook URLs always have a host.
+    let url = request.url();
+    let host = url.host_str().unwrap();
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let addrs = resolve(network, &host, url.port_or_known_default().unwrap()).await?;
+
+    let response = future::timeout(TIMEOUT, send(network, &addrs, &host, request))
+        .await
+        .unwrap_or_else(|e| Err(e.into()))
+        .map_err(Error::Request)?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(Error::Response(status))
+    }
+}
+
+/// Sends notifications to webhooks.
+pub struct Webhook;
+
+impl Notifier for Webhook {
+    type Address = WebhookUrl;
+
+    async fn notify(
+        &self,
+        project: &Project,
+        to: &WebhookUrl,
+        notification: &Notification,
+    ) -> Result<(), notifier::Error> {
+        let payload = Payload::branches_reached(project, to, notification);
+        Ok(post(to, &payload).await?)
+    }
+
+    async fn digest(
+        &self,
+        to: &WebhookUrl,
+        notifications: &[(&Project, &Notification)],
+    ) -> Result<(), notifier::Error> {
+        let payload = Payload::Digest {
+            notifications: notifications
+                .iter()
+                .map(|(project, notification)| Payload::branches_reached(project, to, notification))
+                .collect(),
+        };
+        Ok(post(to, &payload).await?)
+    }
+
+    async fn confirm(
+        &self,
+        project: &Project,
+        to: &WebhookUrl,
+        target: &Target<'_>,
+        link: &str,
+    ) -> Result<(), notifier::Error> {
+        let (pr, pr_title, filter) = match *target {
+            Target::Pr { number, title } => (Some(number), Some(title), None),
+            Target::Filter(filter) => (None, None, Some(filter)),
+        };
+        let payload = Payload::Confirm {
+            repo: &project.name,
+            pr,
+            pr_title,
+            pr_url: pr.map(|pr| project.pull_link(pr)),
+            filter,
+            confirm_url: link,
+            secret: secret(to),
+        };
+        Ok(post(to, &payload).await?)
+    }
+
+    async fn manage(&self, to: &WebhookUrl, link: &str) -> Result<(), notifier::Error> {
+        let payload = Payload::Manage { manage_url: link };
+        Ok(post(to, &payload).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        // From RFC 4231, test case 2.
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[async_std::test]
+    async fn hosts() {
+        for host in ["localhost", "127.0.0.1", "::1"] {
+            assert!(
+                matches!(resolve(&Internet, host, 443).await, Err(Error::Address(_))),
+                "{}",
+                host
+            );
+        }
+        resolve(&Internet, "93.184.215.14", 443).await.unwrap();
+    }
+
+    /// A host that only points somewhere public the first time it's
+    /// looked up.
+    #[derive(Default)]
+    struct Rebinding {
+        lookups: Mutex<Vec<String>>,
+        connections: Mutex<Vec<SocketAddr>>,
+    }
+
+    impl Network for Rebinding {
+        async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            let mut lookups = self.lookups.lock().unwrap();
+            lookups.push(host.to_string());
+            let ip: IpAddr = if lookups.len() == 1 {
+                "93.184.215.14".parse().unwrap()
+            } else {
+                "127.0.0.1".parse().unwrap()
+            };
+            Ok(vec![SocketAddr::new(ip, port)])
+        }
+
+        async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+            self.connections.lock().unwrap().push(addr);
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    #[async_std::test]
+    async fn rebinding() {
+        let network = Rebinding::default();
+        let request = Request::post(Url::parse("https://hook.example/").unwrap());
+        let result = deliver(&network, request).await;
+        assert!(matches!(result, Err(Error::Request(_))), "{:?}", result);
+
+        // The host is only looked up once, and what it pointed to when it
+        // was checked is what's connected to.
+        assert_eq!(*network.lookups.lock().unwrap(), ["hook.example"]);
+        assert_eq!(
+            *network.connections.lock().unwrap(),
+            ["93.184.215.14:443".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn payload() {
+        let branches = [Branch {
+            name: "master".to_string(),
+            hydra_link: None,
+        }];
+        let payload = Payload::BranchesReached {
+            repo: "nixpkgs",
+            pr: "123".parse().unwrap(),
+            pr_title: "title",
+            pr_url: "https://github.com/NixOS/nixpkgs/pull/123".to_string(),
+            branches: &branches,
+            last: true,
+            unsubscribe_url: None,
+            filter: None,
+            unsubscribe_filter_url: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({
+                "event": "branches_reached",
+                "repo": "nixpkgs",
+                "pr": 123,
+                "pr_title": "title",
+                "pr_url": "https://github.com/NixOS/nixpkgs/pull/123",
+                "branches": [{ "name": "master", "hydra_link": null }],
+                "last": true,
+            })
+        );
+
+        let filter = "label:security".parse().unwrap();
+        let payload = Payload::BranchesReached {
+            repo: "nixpkgs",
+            pr: "123".parse().unwrap(),
+            pr_title: "title",
+            pr_url: "https://github.com/NixOS/nixpkgs/pull/123".to_string(),
+            branches: &branches,
+            last: false,
+            unsubscribe_url: Some("https://example.com/unsubscribe?token=a".to_string()),
+            filter: Some(&filter),
+            unsubscribe_filter_url: Some("https://example.com/unsubscribe?token=b".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({
+                "event": "branches_reached",
+                "repo": "nixpkgs",
+                "pr": 123,
+                "pr_title": "title",
+                "pr_url": "https://github.com/NixOS/nixpkgs/pull/123",
+                "branches": [{ "name": "master", "hydra_link": null }],
+                "last": false,
+                "unsubscribe_url": "https://example.com/unsubscribe?token=a",
+                "filter": "label:security",
+                "unsubscribe_filter_url": "https://example.com/unsubscribe?token=b",
+            })
+        );
+    }
+}